@@ -3,10 +3,23 @@ use std::net::TcpStream;
 use std::sync::mpsc::{self, Receiver};
 use std::thread;
 
+// The whole lines at the start of `pending`, newlines included. Only whole lines
+// are decoded, a read can end halfway through a char.
+fn take_lines(pending: &mut Vec<u8>) -> Vec<String> {
+    let mut lines = vec![];
+    while let Some(pos) = pending.iter().position(|b| *b == b'\n') {
+        let line: Vec<u8> = pending.drain(..=pos).collect();
+        lines.push(String::from_utf8_lossy(&line).into_owned());
+    }
+    lines
+}
+
 fn reader(mut stream: TcpStream) {
     let mut buf = vec![0u8; 1024];
+    // Bytes read so far that don't make a whole line yet
+    let mut pending = vec![];
     loop {
-        let payload = match stream.read(&mut buf) {
+        match stream.read(&mut buf) {
             Err(e) => {
                 eprintln!("Error: {e}");
                 std::process::exit(1);
@@ -15,16 +28,18 @@ fn reader(mut stream: TcpStream) {
                 eprintln!("Server gave up for the night");
                 std::process::exit(1);
             }
-            Ok(n) => std::str::from_utf8(&buf[..n]).unwrap(),
+            Ok(n) => pending.extend_from_slice(&buf[..n]),
         };
-        eprintln!("{payload}");
+        for line in take_lines(&mut pending) {
+            eprint!("{line}");
+        }
     }
 }
 
 fn writer(mut stream: TcpStream, rx: Receiver<Vec<u8>>) {
     while let Ok(bytes) = rx.recv() {
-        stream.write(&bytes);
-        stream.flush();
+        let _ = stream.write_all(&bytes);
+        let _ = stream.flush();
     }
 }
 
@@ -51,6 +66,21 @@ fn main() {
     while let Some(Ok(mut line)) = lines.next() {
         eprintln!("you said: {line}");
         line.push('\n');
-        sender.send(line.into_bytes());
+        let _ = sender.send(line.into_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn char_split_across_reads() {
+        // 'é' is two bytes, the first read ends between them
+        let mut pending = b"caf\xc3".to_vec();
+        assert!(take_lines(&mut pending).is_empty());
+        pending.extend_from_slice(b"\xa9\nnext");
+        assert_eq!(take_lines(&mut pending), ["café\n"]);
+        assert_eq!(pending, b"next");
     }
 }
@@ -1,48 +1,92 @@
-use std::io::{stdin, ErrorKind, Read, Write};
+use std::io::{stdin, Read, Write};
 use std::net::TcpStream;
-use std::sync::mpsc::{self, Receiver};
 use std::thread;
-use std::time::Duration;
 
-fn handle_stream(mut stream: TcpStream, rx: Receiver<Vec<u8>>) {
+// The whole lines at the start of `pending`, newlines included. Only whole lines
+// are decoded, a read can end halfway through a char.
+fn take_lines(pending: &mut Vec<u8>) -> Vec<String> {
+    let mut lines = vec![];
+    while let Some(pos) = pending.iter().position(|b| *b == b'\n') {
+        let line: Vec<u8> = pending.drain(..=pos).collect();
+        lines.push(String::from_utf8_lossy(&line).into_owned());
+    }
+    lines
+}
+
+// Blocks until the server sends something, so lines are written to `out` as
+// soon as they arrive. Returns once the server hangs up or the read fails.
+fn handle_stream(mut stream: impl Read, mut out: impl Write) {
     let mut buf = vec![0u8; 1024];
-    stream.set_nonblocking(true);
+    // Bytes read so far that don't make a whole line yet
+    let mut pending = vec![];
 
     loop {
-        // write
-        if let Ok(bytes) = rx.try_recv() {
-            stream.write(&bytes);
-            stream.flush();
-        }
-
-        // read
-        let payload = match stream.read(&mut buf) {
-            Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                thread::sleep(Duration::from_millis(20));
-                continue;
-            }
-            Err(_) | Ok(0) => {
-                eprintln!("Ooops");
-                std::process::exit(1);
-            }
-            Ok(n) => std::str::from_utf8(&buf[..n]).unwrap(),
+        match stream.read(&mut buf) {
+            Err(_) | Ok(0) => return,
+            Ok(n) => pending.extend_from_slice(&buf[..n]),
         };
-        eprintln!("{payload}");
+        for line in take_lines(&mut pending) {
+            let _ = out.write_all(line.as_bytes());
+        }
     }
 }
 
 fn main() {
-    let stream = TcpStream::connect("127.0.0.1:5555").unwrap();
-    let (sender, receiver) = mpsc::channel();
+    let mut stream = TcpStream::connect("127.0.0.1:5555").unwrap();
 
-    thread::spawn(move || handle_stream(stream, receiver));
+    thread::spawn({
+        let stream = stream.try_clone().unwrap();
+        move || {
+            handle_stream(stream, std::io::stderr());
+            eprintln!("Ooops");
+            std::process::exit(1);
+        }
+    });
 
-    // Read forever and ever
+    // Read forever and ever, writing each line straight to the server
     let stdin = stdin();
     let mut lines = stdin.lines();
     while let Some(Ok(mut line)) = lines.next() {
         eprintln!("you said: {line}");
         line.push('\n');
-        sender.send(line.into_bytes());
+        let _ = stream.write_all(line.as_bytes());
+        let _ = stream.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn char_split_across_reads() {
+        // 'é' is two bytes, the first read ends between them
+        let mut pending = b"caf\xc3".to_vec();
+        assert!(take_lines(&mut pending).is_empty());
+        pending.extend_from_slice(b"\xa9\nnext");
+        assert_eq!(take_lines(&mut pending), ["café\n"]);
+        assert_eq!(pending, b"next");
+    }
+
+    // Hands out its chunks one read at a time, like a socket would
+    struct Chunks(Vec<&'static [u8]>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn prints_whole_lines_until_hung_up() {
+        let mut out = vec![];
+        // The half line at the end is never printed, the server hung up before finishing it
+        handle_stream(Chunks(vec![b"* welcome\nal", b"ice: hi\n", b"bob: b"]), &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "* welcome\nalice: hi\n");
     }
 }
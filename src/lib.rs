@@ -0,0 +1,4803 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, Lines};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver};
+use tokio::sync::{oneshot, watch};
+use tokio::task::AbortHandle;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+pub type Room = String;
+type RoomSender = mpsc::Sender<Request>;
+type RoomReceiver = Receiver<Request>;
+
+// Max number of pending scheduled messages per user
+const MAX_SCHEDULED: usize = 10;
+// Longest delay `schedule` takes, in seconds
+const MAX_SCHEDULE_DELAY: u64 = 24 * 60 * 60;
+// Number of chars of a scheduled message shown by `schedules`
+const SCHEDULE_PREVIEW_LEN: usize = 20;
+// Wrong `auth` tokens a connection may send before it is dropped
+const MAX_AUTH_FAILURES: usize = 3;
+// Period of `Config::command_rate`
+const COMMAND_RATE_PERIOD: Duration = Duration::from_secs(5);
+// Min time between two typing notices from the same user
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+// Wait after a failed accept, times the number of failures in a row (up to 10)
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// Max time a client gets to finish the tls handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Connection ids, unique across every server in the process
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+
+// -----------------------------------------------------------------------------
+//   - Config -
+//   Runtime settings. The binary reads them from the command line and
+//   environment with `Config::from_args`, embedders start from `Config::default()`.
+//   * --echo: run as an echo server, see `handle_echo`
+//   * --bind <addr:port>: address to listen on (default 127.0.0.1:5555),
+//     `[::]:<port>` listens on both IPv6 and IPv4
+//   * --unix <path>: also listen on a Unix domain socket at <path>, which
+//     is removed again on shutdown. These connections never use TLS.
+//   * --no-tcp: only listen on the `--unix` socket
+//   * --health <addr:port>: where the health check listens, see `health`
+//     (default 127.0.0.1:5556)
+//   * --no-health: don't run a health check
+//   * --length-framing: messages are prefixed with their length instead of
+//     ending in a newline, see `LengthFrame`
+//   * --json: every line in and out is a JSON object, see `WireCommand`
+//     and `WireEvent`
+//   * --timestamps: chat messages start with the time they were sent,
+//     `[hh:mm:ss] ` in UTC
+//   * --tls-cert <path> --tls-key <path>: PEM certificate chain and private
+//     key. If given, every connection has to speak TLS, otherwise plaintext.
+//     They are read by `Server::bind`, which fails if they can't be used.
+//   * --debug-commands: enable `parse <line>`, which shows what the parser
+//     makes of a line, and `framestate`, which shows what the frame holds
+//   * CHATTERY_AUTH_TOKEN: if set, every user has to `auth <token>`
+//     after picking a username and before they can do anything else.
+//     Connections get MAX_AUTH_FAILURES tries.
+//   * CHATTERY_ADMIN_TOKEN: if set, users who send `admin <token>` become
+//     admins, who may `link` and `unlink` rooms and see the settings with
+//     `config`. Without it nobody can.
+//   * CHATTERY_LINK_TOKEN: sent with `auth` when a server this one links a
+//     room to asks for a token
+//   * CHATTERY_BOT_TOKEN: a token for service accounts, which may only
+//     use the commands listed in CHATTERY_BOT_COMMANDS (default: `msg`).
+//     Setting either token makes authentication required.
+//   * CHATTERY_ANON_WATCHERS: max number of anonymous, read-only connections
+//     (default 0, which disables them). These send `watch <room>` instead
+//     of a username and can only watch rooms.
+//   * CHATTERY_STRICT_USERNAME: if set, a connection that sends a command
+//     before picking a username is disconnected (rather than being given
+//     the command as its username)
+//   * CHATTERY_ACCEPT_RATE: max new connections per second (default 100)
+//   * CHATTERY_MAX_CONNECTIONS: max connections at once (default 1024).
+//     Connections over it are told `* server full` and closed.
+//   * CHATTERY_COMMAND_RATE: max commands per connection in 5 seconds
+//     (default 10)
+//   * CHATTERY_SOFT_WRAP: if set, users can declare their terminal width
+//     with `width <cols>` and long lines are wrapped to fit it
+//   * CHATTERY_BANNED_WORDS: path to a file with one banned word per line
+//   * CHATTERY_BANNED_WORDS_MODE: `reject` (default) or `censor`
+//   * CHATTERY_IDLE_TIMEOUT: seconds a connection may go without sending
+//     anything before it is disconnected (default 300)
+//   * CHATTERY_PING_INTERVAL: if set, seconds of silence after which the server
+//     sends `ping`. A connection that doesn't answer with `pong` before
+//     the next interval is up is disconnected.
+//   * CHATTERY_WRITER_CAPACITY: number of messages queued for a connection
+//     before messages to it are dropped (default 32)
+//   * CHATTERY_MAX_MUTES: max number of users one user can mute (default 200)
+//   * CHATTERY_SESSION_TTL: seconds after a disconnect that the session can
+//     still be resumed with `resume <token>` (default 60)
+//   * CHATTERY_HISTORY: number of messages each room keeps for people
+//     joining later (default 20, 0 disables history)
+//   * CHATTERY_HISTORY_COMPRESS: if set, history messages of at least this
+//     many bytes are kept compressed in memory, see `StoredLine`
+//   * CHATTERY_HISTORY_FILE: if set, chat messages are appended to this file
+//     and each room's history is read back from it on startup. It is rotated
+//     to `<path>.1` at 16 MiB, see `HistoryLog`.
+//   * CHATTERY_ROOMS_FILE: if set, the rooms' topics, motds and greetings and
+//     the bans are saved to this file on shutdown and restored on startup,
+//     see `SavedRooms`
+// -----------------------------------------------------------------------------
+pub struct Config {
+    /// Run as an echo server, see `handle_echo`
+    pub echo: bool,
+    pub length_framing: bool,
+    pub json: bool,
+    pub timestamps: bool,
+    /// Enables `parse <line>` and `framestate`
+    pub debug_commands: bool,
+    /// Where the time for `timestamps` comes from, `SystemTime::now` unless testing
+    pub clock: fn() -> SystemTime,
+    /// Where `Server::bind` listens
+    pub bind: SocketAddr,
+    /// False with `--no-tcp`, `Server::bind` then only listens on `unix`
+    pub tcp: bool,
+    pub unix: Option<PathBuf>,
+    /// Loaded by `Server::bind`, None if connections are plaintext
+    pub tls: Option<TlsFiles>,
+    /// Where the health check listens, None for no health check
+    pub health: Option<SocketAddr>,
+    /// None if no authentication is required
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    pub anonymous_watchers: usize,
+    pub strict_username: bool,
+    pub accept_rate: u32,
+    /// Max number of connections at once, the ones over it are turned away
+    pub max_connections: usize,
+    /// Max number of commands per connection in 5 seconds
+    pub command_rate: u32,
+    pub soft_wrap: bool,
+    pub word_filter: Option<WordFilter>,
+    pub history_len: usize,
+    /// History messages this long or longer are kept compressed, None to never compress
+    pub history_compress: Option<usize>,
+    pub history_file: Option<String>,
+    /// Where rooms are saved on shutdown and restored from on startup, None to not save them
+    pub rooms_file: Option<String>,
+    pub idle_timeout: Duration,
+    pub ping_interval: Option<Duration>,
+    pub writer_capacity: usize,
+    /// Max number of users one user can `mute`
+    pub max_mutes: usize,
+    /// How long after disconnecting a session can be resumed
+    pub session_ttl: Duration,
+    /// Makes users who send `admin <token>` admins, None for no admins
+    pub admin_token: Option<String>,
+    /// What `link` authenticates with on the other server, if it asks
+    pub link_token: Option<String>,
+}
+
+/// The defaults of the command line, except that there is no health check
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            echo: false,
+            length_framing: false,
+            json: false,
+            timestamps: false,
+            debug_commands: false,
+            clock: SystemTime::now,
+            bind: SocketAddr::from(([127, 0, 0, 1], 5555)),
+            tcp: true,
+            unix: None,
+            tls: None,
+            health: None,
+            authenticator: None,
+            anonymous_watchers: 0,
+            strict_username: false,
+            accept_rate: 100,
+            max_connections: 1024,
+            command_rate: 10,
+            soft_wrap: false,
+            word_filter: None,
+            history_len: 20,
+            history_compress: None,
+            history_file: None,
+            rooms_file: None,
+            idle_timeout: Duration::from_secs(300),
+            ping_interval: None,
+            writer_capacity: 32,
+            max_mutes: 200,
+            session_ttl: Duration::from_secs(60),
+            admin_token: None,
+            link_token: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the settings from the command line and environment, see above.
+    /// The error is meant to be shown to whoever started the server.
+    pub fn from_args() -> Result<Self, String> {
+        let defaults = Self::default();
+        let word_filter = match std::env::var("CHATTERY_BANNED_WORDS") {
+            Ok(path) => {
+                let mode = match std::env::var("CHATTERY_BANNED_WORDS_MODE").as_deref() {
+                    Ok("censor") => FilterMode::Censor,
+                    _ => FilterMode::Reject,
+                };
+                Some(WordFilter::load(&path, mode).map_err(|e| format!("failed to load banned words '{path}': {e}"))?)
+            }
+            Err(_) => None,
+        };
+
+        let bot_commands = std::env::var("CHATTERY_BOT_COMMANDS").unwrap_or_else(|_| "msg".into());
+        let tokens = TokenAuthenticator {
+            auth_token: std::env::var("CHATTERY_AUTH_TOKEN").ok(),
+            bot_token: std::env::var("CHATTERY_BOT_TOKEN").ok(),
+            bot_commands: bot_commands.split(',').map(|cmd| cmd.trim().to_string()).collect(),
+        };
+        let authenticator: Option<Arc<dyn Authenticator>> = if tokens.auth_token.is_some() || tokens.bot_token.is_some() {
+            Some(Arc::new(tokens))
+        } else {
+            None
+        };
+
+        let args: Vec<String> = std::env::args().collect();
+        let arg_value = |name: &str| args.iter().position(|arg| arg == name).and_then(|pos| args.get(pos + 1));
+        let bind = match args.iter().any(|arg| arg == "--bind") {
+            true => parse_bind(arg_value("--bind").map(String::as_str).unwrap_or_default())?,
+            false => defaults.bind,
+        };
+
+        let tls = match (arg_value("--tls-cert"), arg_value("--tls-key")) {
+            (Some(cert), Some(key)) => Some(TlsFiles { cert: cert.into(), key: key.into() }),
+            (None, None) => None,
+            _ => return Err("--tls-cert and --tls-key have to be given together".into()),
+        };
+
+        let health = match (args.iter().any(|arg| arg == "--no-health"), arg_value("--health")) {
+            (true, _) => None,
+            (false, Some(addr)) => Some(parse_bind(addr)?),
+            (false, None) => Some(SocketAddr::from(([127, 0, 0, 1], 5556))),
+        };
+
+        let unix = arg_value("--unix").map(PathBuf::from);
+        let tcp = !args.iter().any(|arg| arg == "--no-tcp");
+        if !tcp && unix.is_none() {
+            return Err("--no-tcp needs a --unix socket to listen on".into());
+        }
+
+        let env = |name: &str| std::env::var(name).ok();
+        Ok(Self {
+            echo: args.iter().any(|arg| arg == "--echo"),
+            length_framing: args.iter().any(|arg| arg == "--length-framing"),
+            json: args.iter().any(|arg| arg == "--json"),
+            timestamps: args.iter().any(|arg| arg == "--timestamps"),
+            debug_commands: args.iter().any(|arg| arg == "--debug-commands"),
+            clock: defaults.clock,
+            bind,
+            tcp,
+            unix,
+            tls,
+            health,
+            authenticator,
+            anonymous_watchers: env("CHATTERY_ANON_WATCHERS").and_then(|max| max.parse().ok()).unwrap_or(defaults.anonymous_watchers),
+            strict_username: std::env::var_os("CHATTERY_STRICT_USERNAME").is_some(),
+            accept_rate: env("CHATTERY_ACCEPT_RATE").and_then(|rate| rate.parse().ok()).unwrap_or(defaults.accept_rate),
+            max_connections: env("CHATTERY_MAX_CONNECTIONS").and_then(|max| max.parse().ok()).unwrap_or(defaults.max_connections),
+            command_rate: env("CHATTERY_COMMAND_RATE").and_then(|rate| rate.parse().ok()).unwrap_or(defaults.command_rate),
+            soft_wrap: std::env::var_os("CHATTERY_SOFT_WRAP").is_some(),
+            word_filter,
+            history_len: env("CHATTERY_HISTORY").and_then(|len| len.parse().ok()).unwrap_or(defaults.history_len),
+            history_compress: env("CHATTERY_HISTORY_COMPRESS").and_then(|len| len.parse().ok()),
+            history_file: env("CHATTERY_HISTORY_FILE"),
+            rooms_file: env("CHATTERY_ROOMS_FILE"),
+            idle_timeout: env("CHATTERY_IDLE_TIMEOUT")
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.idle_timeout),
+            writer_capacity: env("CHATTERY_WRITER_CAPACITY")
+                .and_then(|capacity| capacity.parse().ok())
+                .filter(|capacity| *capacity > 0)
+                .unwrap_or(defaults.writer_capacity),
+            ping_interval: env("CHATTERY_PING_INTERVAL").and_then(|secs| secs.parse().ok()).map(Duration::from_secs),
+            max_mutes: env("CHATTERY_MAX_MUTES").and_then(|max| max.parse().ok()).unwrap_or(defaults.max_mutes),
+            session_ttl: env("CHATTERY_SESSION_TTL")
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.session_ttl),
+            admin_token: env("CHATTERY_ADMIN_TOKEN"),
+            link_token: env("CHATTERY_LINK_TOKEN"),
+        })
+    }
+
+    // Every setting as `<name> <value>`, for `config`.
+    // Of the tokens it only says whether they are set.
+    fn describe(&self) -> Vec<String> {
+        let secret = |token: &Option<String>| if token.is_some() { "<redacted>" } else { "none" };
+        let on = |set: bool| if set { "on" } else { "off" };
+        let or_none = |value: Option<String>| value.unwrap_or_else(|| "none".into());
+        vec![
+            format!("echo {}", on(self.echo)),
+            format!("length_framing {}", on(self.length_framing)),
+            format!("json {}", on(self.json)),
+            format!("timestamps {}", on(self.timestamps)),
+            format!("debug_commands {}", on(self.debug_commands)),
+            format!("bind {}", self.bind),
+            format!("tcp {}", on(self.tcp)),
+            format!("unix {}", or_none(self.unix.as_ref().map(|path| path.display().to_string()))),
+            format!("tls {}", on(self.tls.is_some())),
+            format!("health {}", or_none(self.health.map(|addr| addr.to_string()))),
+            format!("authentication {}", on(self.authenticator.is_some())),
+            format!("anonymous_watchers {}", self.anonymous_watchers),
+            format!("strict_username {}", on(self.strict_username)),
+            format!("accept_rate {}", self.accept_rate),
+            format!("max_connections {}", self.max_connections),
+            format!("command_rate {}", self.command_rate),
+            format!("soft_wrap {}", on(self.soft_wrap)),
+            format!("word_filter {}", on(self.word_filter.is_some())),
+            format!("history_len {}", self.history_len),
+            format!("history_compress {}", or_none(self.history_compress.map(|len| len.to_string()))),
+            format!("history_file {}", or_none(self.history_file.clone())),
+            format!("rooms_file {}", or_none(self.rooms_file.clone())),
+            format!("idle_timeout {}s", self.idle_timeout.as_secs()),
+            format!("ping_interval {}", or_none(self.ping_interval.map(|interval| format!("{}s", interval.as_secs())))),
+            format!("writer_capacity {}", self.writer_capacity),
+            format!("max_mutes {}", self.max_mutes),
+            format!("session_ttl {}s", self.session_ttl.as_secs()),
+            format!("admin_token {}", secret(&self.admin_token)),
+            format!("link_token {}", secret(&self.link_token)),
+        ]
+    }
+
+    // Strip control chars (but tabs), so nobody can send escape sequences or
+    // carriage returns to other people's terminals. Stripped rather than refused,
+    // as they mostly end up in messages by accident, e.g. pasted text.
+    // Then run it through the banned words filter, if there is one.
+    fn filter_message(&self, mut msg: String) -> Option<String> {
+        msg.retain(|c| c == '\t' || !c.is_control());
+        match &self.word_filter {
+            Some(filter) => filter.apply(&msg),
+            None => Some(msg),
+        }
+    }
+}
+
+// `--bind` and `--health` take an ip address and a port, e.g. 0.0.0.0:9000 or [::]:9000
+fn parse_bind(addr: &str) -> Result<SocketAddr, String> {
+    addr.parse().map_err(|_| format!("invalid bind address '{addr}', expected <ip>:<port> or [<ipv6>]:<port>"))
+}
+
+// Binding to `[::]` takes IPv4 connections as well, whatever the OS default is
+fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    let dual_stack = addr.is_ipv6() && addr.ip().is_unspecified();
+    if dual_stack {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    let family = match addr {
+        SocketAddr::V4(_) => "ipv4",
+        SocketAddr::V6(_) if dual_stack => "ipv6 and ipv4",
+        SocketAddr::V6(_) => "ipv6",
+    };
+    info!("listening on {addr} ({family})");
+    TcpListener::from_std(socket.into())
+}
+
+// A socket file left behind by a server that didn't shut down cleanly is
+// removed. If a server still answers on it, or there is anything else
+// at `path`, it is left alone and binding fails.
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> std::io::Result<UnixListener> {
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            let e = format!("a server is already listening on {}", path.display());
+            return Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, e));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("listening on {} (unix)", path.display());
+    Ok(listener)
+}
+
+// A connection from either listener
+enum Incoming {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+// Tells a connection the server is full and closes it. In a task of its own,
+// so a client that doesn't read can't hold up accepting others. Over tls the
+// notice would be unreadable before a handshake, so it's only closed.
+fn turn_away(stream: Incoming, config: &Config) {
+    if config.tls.is_some() {
+        return;
+    }
+    let notice = Outgoing::from(&b"* server full\n"[..]);
+    let notice = if config.json { WireEvent::encode(&notice) } else { notice.bytes.to_vec() };
+    tokio::spawn(async move {
+        let _ = match stream {
+            Incoming::Tcp(mut stream) => stream.write_all(&notice).await,
+            #[cfg(unix)]
+            Incoming::Unix(mut stream) => stream.write_all(&notice).await,
+        };
+    });
+}
+
+// Accepts until it works. Errors are logged and waited out, longer the more
+// there are in a row, e.g. being out of file descriptors passes once some
+// connections close.
+async fn accept_retrying<T, F: Future<Output = std::io::Result<T>>>(mut accept: impl FnMut() -> F) -> T {
+    let mut failures = 0;
+    loop {
+        match accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                warn!("failed to accept connection: {e}");
+                failures += 1;
+                tokio::time::sleep(ACCEPT_BACKOFF * failures.min(10)).await;
+            }
+        }
+    }
+}
+
+// The next connection from whichever listeners there are
+async fn accept(tcp: Option<&TcpListener>, #[cfg(unix)] unix: Option<&UnixListener>) -> std::io::Result<Incoming> {
+    let tcp = async {
+        match tcp {
+            Some(listener) => listener.accept().await.map(|(stream, _addr)| Incoming::Tcp(stream)),
+            None => std::future::pending().await,
+        }
+    };
+    #[cfg(unix)]
+    let unix = async {
+        match unix {
+            Some(listener) => listener.accept().await.map(|(stream, _addr)| Incoming::Unix(stream)),
+            None => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let unix = std::future::pending();
+    tokio::select! {
+        accepted = tcp => accepted,
+        accepted = unix => accepted,
+    }
+}
+
+/// The PEM certificate chain and private key connections are served with over TLS
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsFiles {
+    // The error says which file is the problem, for whoever started the server
+    fn load(&self) -> std::io::Result<TlsAcceptor> {
+        let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+        let open = |path: &Path| {
+            std::fs::File::open(path)
+                .map(std::io::BufReader::new)
+                .map_err(|e| std::io::Error::new(e.kind(), format!("failed to open '{}': {e}", path.display())))
+        };
+        let certs = rustls_pemfile::certs(&mut open(&self.cert)?)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(format!("invalid certificate '{}': {e}", self.cert.display())))?;
+        let key = rustls_pemfile::private_key(&mut open(&self.key)?)
+            .map_err(|e| invalid(format!("invalid private key '{}': {e}", self.key.display())))?
+            .ok_or_else(|| invalid(format!("no private key in '{}'", self.key.display())))?;
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| invalid(format!("invalid tls config: {e}")))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+// -----------------------------------------------------------------------------
+//   - Server state -
+//   What every connection of a `Server` shares, apart from the rooms task:
+//   the counters reported with `stats` and the usernames in use.
+//   Each `Server` has its own, so servers in the same process don't mix.
+// -----------------------------------------------------------------------------
+struct ServerState {
+    active_connections: AtomicUsize,
+    anonymous_connections: AtomicUsize,
+    total_connections: AtomicUsize,
+    messages_routed: AtomicUsize,
+    rooms_created: AtomicUsize,
+    // Counts every message kept in a room's history, so `recall` can put
+    // messages from different rooms in order
+    next_post: AtomicUsize,
+    // Names of everyone connected, so no two users share one
+    usernames: Mutex<HashSet<String>>,
+}
+
+impl Default for ServerState {
+    fn default() -> Self {
+        Self {
+            active_connections: AtomicUsize::new(0),
+            anonymous_connections: AtomicUsize::new(0),
+            total_connections: AtomicUsize::new(0),
+            messages_routed: AtomicUsize::new(0),
+            rooms_created: AtomicUsize::new(0),
+            // Restored messages are posted as 0
+            next_post: AtomicUsize::new(1),
+            usernames: Mutex::default(),
+        }
+    }
+}
+
+impl ServerState {
+    // Take a username for this connection, false if someone else has it
+    fn claim_username(&self, username: &str) -> bool {
+        self.usernames.lock().unwrap().insert(username.to_string())
+    }
+
+    // Without taking it, e.g. to tell someone early that they will have to pick another
+    fn username_taken(&self, username: &str) -> bool {
+        self.usernames.lock().unwrap().contains(username)
+    }
+
+    fn release_username(&self, username: &str) {
+        self.usernames.lock().unwrap().remove(username);
+    }
+}
+
+// -----------------------------------------------------------------------------
+//   - Authentication -
+//   Checks the `auth <token>` a user sends after picking a username.
+//   Implement `Authenticator` to check tokens against something other
+//   than the tokens from the environment (a file, another service, ...).
+// -----------------------------------------------------------------------------
+pub enum AuthResult {
+    Accepted,
+    /// Accepted, but only the listed commands may be used
+    Restricted(HashSet<String>),
+    Rejected,
+}
+
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = AuthResult> + Send + 'a>>;
+
+/// Set `Config::authenticator` to require `auth <token>` from every user
+pub trait Authenticator: Send + Sync {
+    /// Checks the token `user` sent with `auth <token>`
+    fn verify<'a>(&'a self, user: &'a str, token: &'a str) -> AuthFuture<'a>;
+}
+
+// The built in authenticator: one token for users, and one for service
+// accounts that are restricted to `bot_commands`
+struct TokenAuthenticator {
+    auth_token: Option<String>,
+    bot_token: Option<String>,
+    bot_commands: HashSet<String>,
+}
+
+impl Authenticator for TokenAuthenticator {
+    fn verify<'a>(&'a self, _user: &'a str, token: &'a str) -> AuthFuture<'a> {
+        let result = if self.auth_token.as_deref() == Some(token) {
+            AuthResult::Accepted
+        } else if self.bot_token.as_deref() == Some(token) {
+            AuthResult::Restricted(self.bot_commands.clone())
+        } else {
+            AuthResult::Rejected
+        };
+        Box::pin(std::future::ready(result))
+    }
+}
+
+// -----------------------------------------------------------------------------
+//   - Word filter -
+//   Messages are split into words (runs of alphanumeric chars)
+//   and each word is looked up, case insensitive, in the set of banned words.
+//   This way "class" doesn't match "ass", and the cost of a lookup
+//   doesn't grow with the number of banned words.
+// -----------------------------------------------------------------------------
+pub enum FilterMode {
+    // Refuse the whole message
+    Reject,
+    // Replace every banned word with asterisks
+    Censor,
+}
+
+pub struct WordFilter {
+    words: HashSet<String>,
+    mode: FilterMode,
+}
+
+impl WordFilter {
+    /// Reads the banned words from a file with one word per line
+    pub fn load(path: &str, mode: FilterMode) -> std::io::Result<Self> {
+        let words = std::fs::read_to_string(path)?
+            .lines()
+            .map(|word| word.trim().to_lowercase())
+            .filter(|word| !word.is_empty())
+            .collect();
+        Ok(Self { words, mode })
+    }
+
+    // Returns the message to send (censored if need be),
+    // or None if the message should be rejected.
+    fn apply(&self, msg: &str) -> Option<String> {
+        let mut output = String::with_capacity(msg.len());
+        let mut rest = msg;
+
+        while let Some(start) = rest.find(char::is_alphanumeric) {
+            let (before, word) = rest.split_at(start);
+            let end = word.find(|c: char| !c.is_alphanumeric()).unwrap_or(word.len());
+            let (word, after) = word.split_at(end);
+            output.push_str(before);
+
+            if self.words.contains(&word.to_lowercase()) {
+                match self.mode {
+                    FilterMode::Reject => return None,
+                    FilterMode::Censor => output.extend(word.chars().map(|_| '*')),
+                }
+            } else {
+                output.push_str(word);
+            }
+
+            rest = after;
+        }
+
+        output.push_str(rest);
+        Some(output)
+    }
+}
+
+// -----------------------------------------------------------------------------
+//   - User connection state -
+//   Tracks username and user id,
+//   but also makes sure we get a username before we allow the user to chat
+//   (and a valid auth token, if the server requires one)
+// -----------------------------------------------------------------------------
+enum State {
+    Anon,
+    // Anonymous and read-only, never picked a username
+    Spectator(Arc<Sender>),
+    Unauthenticated(Arc<Sender>),
+    User(Arc<Sender>),
+}
+
+// What a connection's writer is handed. Messages that went through a room
+// carry its name, and chat messages their sequence number in the room's
+// history, for the `room` and `seq` fields of `--json` events.
+#[derive(Debug, Clone)]
+struct Outgoing {
+    room: Option<Room>,
+    seq: Option<u64>,
+    bytes: Arc<[u8]>,
+}
+
+impl From<Arc<[u8]>> for Outgoing {
+    fn from(bytes: Arc<[u8]>) -> Self {
+        Self { room: None, seq: None, bytes }
+    }
+}
+
+impl From<&[u8]> for Outgoing {
+    fn from(bytes: &[u8]) -> Self {
+        Self { room: None, seq: None, bytes: bytes.into() }
+    }
+}
+
+impl From<Vec<u8>> for Outgoing {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self { room: None, seq: None, bytes: bytes.into() }
+    }
+}
+
+#[derive(Debug)]
+struct Sender {
+    inner: mpsc::Sender<Outgoing>,
+    id: usize,
+    username: String,
+    stats: Arc<ConnectionStats>,
+    // Set with `verbose on`: explain why commands were dropped
+    verbose: AtomicBool,
+    // Set with `echo on`: get your own messages back from rooms.
+    // Shared with the tasks forwarding rooms, which keep the sender they
+    // subscribed with even after a `nick`
+    echo: Arc<AtomicBool>,
+    // Messages to this user were dropped because their connection is too slow
+    lagging: AtomicBool,
+    // Ids of the users whose messages this user doesn't want to see,
+    // shared like `echo`
+    muted: Arc<Mutex<HashSet<usize>>>,
+    // Picked with `mode raw` before logging in, see `status`
+    raw: bool,
+    // Set with `admin <token>`, shared like `echo`
+    admin: Arc<AtomicBool>,
+    // Set with `invisible on`: `isonline` says this user is offline.
+    // Shared like `echo`.
+    invisible: Arc<AtomicBool>,
+}
+
+// -----------------------------------------------------------------------------
+//   - Connection stats -
+//   Shared between the reader and the writer of a connection,
+//   and reported back to the user with `mystats`
+// -----------------------------------------------------------------------------
+#[derive(Debug)]
+struct ConnectionStats {
+    connected_at: Instant,
+    messages_sent: AtomicUsize,
+    messages_received: AtomicUsize,
+    bytes_read: AtomicUsize,
+    bytes_written: AtomicUsize,
+}
+
+impl ConnectionStats {
+    fn new() -> Self {
+        Self {
+            connected_at: Instant::now(),
+            messages_sent: AtomicUsize::new(0),
+            messages_received: AtomicUsize::new(0),
+            bytes_read: AtomicUsize::new(0),
+            bytes_written: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Sender {
+    // Send a line to this user only. Like everything the rooms task sends,
+    // it goes through `deliver`, so one slow connection can't hold up the others.
+    fn reply(&self, line: &str) {
+        let mut payload = Vec::with_capacity(line.len() + 1);
+        payload.extend(line.as_bytes());
+        payload.push(b'\n');
+        self.deliver(payload);
+    }
+
+    // Send something without waiting on a slow connection.
+    // It's dropped if the connection is backed up, and the user is told
+    // once there is room again.
+    fn deliver(&self, message: impl Into<Outgoing>) {
+        if self.lagging.load(Ordering::Relaxed) {
+            if self.inner.try_send(b"* you are lagging, messages dropped\n"[..].into()).is_err() {
+                return;
+            }
+            self.lagging.store(false, Ordering::Relaxed);
+        }
+        if let Err(TrySendError::Full(_)) = self.inner.try_send(message.into()) {
+            self.lagging.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn is_muted(&self, id: usize) -> bool {
+        self.muted.lock().unwrap().contains(&id)
+    }
+
+    // Diagnostics about dropped commands, only sent in verbose mode
+    fn notice(&self, line: &str) {
+        if self.verbose.load(Ordering::Relaxed) {
+            self.reply(line);
+        }
+    }
+
+    // Tell this user why their command was refused
+    fn reject(&self, rejection: Rejection<'_>) {
+        self.status(&format!("ERR {}", rejection.code()), &rejection.to_string());
+    }
+
+    fn status(&self, terse: &str, prose: &str) {
+        self.reply(if self.raw { terse } else { prose });
+    }
+
+    // A confirmation for people. Raw connections get `OK <command>` from
+    // the rooms task instead.
+    fn confirm(&self, line: &str) {
+        if !self.raw {
+            self.reply(line);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//   - Raw mode -
+//   A connection that sends `mode raw` before its username is answered with
+//   `OK mode raw`, and from then on gets terse status lines instead of prose:
+//   * `OK <command>` once a command is done, after anything it sends back
+//     (e.g. the users for `who`). `OK login <session token>` for the username.
+//   * `ERR <reason code>` when it is refused, the same codes as `Rejection`
+//     and `ParseError::code`
+//   Chat messages and room notices look the same in both modes. The banner
+//   and the username prompt are held back for PROMPT_DELAY after connecting,
+//   and left out if the first line is `mode raw`.
+// -----------------------------------------------------------------------------
+const PROMPT_DELAY: Duration = Duration::from_millis(200);
+
+async fn send_prompt(sender: &mpsc::Sender<Outgoing>) {
+    let prompt = format!("{BANNER}{HELP}enter username\n");
+    let _ = sender.send(prompt.into_bytes().into()).await;
+}
+
+async fn status(sender: &mpsc::Sender<Outgoing>, raw: bool, terse: &str, prose: &str) {
+    let line = if raw { terse } else { prose };
+    let _ = sender.send(format!("{line}\n").into_bytes().into()).await;
+}
+
+// -----------------------------------------------------------------------------
+//   - Rejections -
+//   Every refused command is answered in the same format:
+//   `* <explanation> [<reason code>]`
+//   The explanation is for people, the reason code for programs.
+// -----------------------------------------------------------------------------
+enum Rejection<'a> {
+    NoSuchRoom(&'a str),
+    NotMember(&'a str),
+    // Someone else, for `kick`
+    UserNotMember { username: &'a str, room: &'a str },
+    // For `broadcast`
+    NoRooms,
+    AlreadyJoined(&'a str),
+    NotOperator(&'a str),
+    NotAdmin,
+    Banned(&'a str),
+    NoSuchMessage(u64),
+    AlreadyLinked(&'a str),
+    NotLinked(&'a str),
+    TooManyReactions(u64),
+    NoSuchUser(&'a str),
+    MutesFull,
+    InvalidToken,
+    RoomFull(&'a str),
+    TooManyRooms,
+    Blocked,
+}
+
+impl Rejection<'_> {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::NoSuchRoom(_) => "nosuchroom",
+            Self::NotMember(_) | Self::UserNotMember { .. } | Self::NoRooms => "notmember",
+            Self::AlreadyJoined(_) => "alreadyjoined",
+            Self::NotOperator(_) => "notoperator",
+            Self::NotAdmin => "notadmin",
+            Self::Banned(_) => "banned",
+            Self::NoSuchMessage(_) => "nosuchmessage",
+            Self::AlreadyLinked(_) => "linked",
+            Self::NotLinked(_) => "notlinked",
+            Self::TooManyReactions(_) => "toomanyreactions",
+            Self::NoSuchUser(_) => "nosuchuser",
+            Self::MutesFull => "mutesfull",
+            Self::InvalidToken => "invalidtoken",
+            Self::RoomFull(_) => "full",
+            Self::TooManyRooms => "toomanyrooms",
+            Self::Blocked => "blocked",
+        }
+    }
+}
+
+impl fmt::Display for Rejection<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSuchRoom(room) => write!(f, "* no such room {room}")?,
+            Self::NotMember(room) => write!(f, "* you are not in {room}")?,
+            Self::UserNotMember { username, room } => write!(f, "* {username} is not in {room}")?,
+            Self::NoRooms => write!(f, "* you are not in any rooms")?,
+            Self::AlreadyJoined(room) => write!(f, "* already in {room}")?,
+            Self::NotOperator(room) => write!(f, "* you are not an operator of {room}")?,
+            Self::NotAdmin => write!(f, "* only admins can do that")?,
+            Self::Banned(room) => write!(f, "* you are banned from {room}")?,
+            Self::NoSuchMessage(seq) => write!(f, "* no such message #{seq}")?,
+            Self::AlreadyLinked(room) => write!(f, "* {room} is already linked")?,
+            Self::NotLinked(room) => write!(f, "* {room} is not linked")?,
+            Self::TooManyReactions(seq) => write!(f, "* too many reactions to #{seq}")?,
+            Self::NoSuchUser(username) => write!(f, "* no such user {username}")?,
+            Self::MutesFull => write!(f, "* ignore list full")?,
+            Self::InvalidToken => write!(f, "* invalid token")?,
+            Self::RoomFull(room) => write!(f, "* room {room} is full")?,
+            Self::TooManyRooms => write!(f, "* you have joined too many rooms")?,
+            Self::Blocked => write!(f, "* message blocked")?,
+        }
+        write!(f, " [{}]", self.code())
+    }
+}
+
+impl PartialEq for Sender {
+    fn eq(&self, other: &Sender) -> bool {
+        self.id == other.id
+    }
+}
+
+// -----------------------------------------------------------------------------
+//   - Overview -
+// -----------------------------------------------------------------------------
+// * Commands
+// * Parse / frame input
+// * Rooms
+//
+// 1. Listener listens for incoming connections
+
+// Scenario 1: bytes\n
+//
+// Scenario 2: by
+// Scenario 2a: t
+// Scenario 2b: es\n
+//
+// Scenario 3: bytes\nbytes\nbyt
+// Scenario 3: bytes\nbyt
+
+// Longest line we are willing to buffer, newline included
+const MAX_LINE: usize = 8 * 1024;
+// How far an admin can raise it for their connection, see `buflimit`
+const MAX_BUFLIMIT: usize = 1024 * 1024;
+
+/// What `Framing::frame` found in the buffered bytes
+pub enum FrameResult {
+    /// A whole message, trailing newline included
+    Complete(Vec<u8>),
+    /// No newline yet, read some more
+    Incomplete,
+    /// The line went past `Framing::max_line` and was dropped
+    TooLong,
+}
+
+/// Newline delimited framing, the default
+pub struct Frame {
+    buf: Vec<u8>,
+    index: usize,
+    // Set after dropping a line that is too long,
+    // the rest of that line is skipped up to the next newline
+    discarding: bool,
+    max_line: usize,
+}
+
+/// Splits the bytes read from a connection into messages.
+/// `Frame` splits on newlines, `LengthFrame` reads length prefixes.
+pub trait Framing: Send {
+    /// The part of the buffer to read into next
+    fn unfilled(&mut self) -> &mut [u8];
+    /// Marks `bytes_read` bytes of `unfilled` as read
+    fn update(&mut self, bytes_read: usize);
+    /// The next message, if a whole one has been read
+    fn frame(&mut self) -> FrameResult;
+    /// Debug: buffer length, index and the number of bytes still waiting to be framed
+    fn state(&self) -> (usize, usize, usize);
+    /// The longest message taken, newline included. `MAX_LINE` unless
+    /// changed with `set_max_line`.
+    fn max_line(&self) -> usize;
+    fn set_max_line(&mut self, max_line: usize);
+}
+
+// The framing picked at startup, see `--length-framing`
+fn new_framing(config: &Config) -> Box<dyn Framing> {
+    if config.length_framing {
+        Box::new(LengthFrame::new())
+    } else {
+        Box::new(Frame::new())
+    }
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Self {
+            buf: vec![0; 1024],
+            index: 0,
+            discarding: false,
+            max_line: MAX_LINE,
+        }
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Framing for Frame {
+    fn update(&mut self, bytes_read: usize) {
+        self.index += bytes_read;
+    }
+
+    // The part of the buffer that hasn't been read into yet.
+    // A full buffer is grown first: reading into an empty slice
+    // returns `Ok(0)`, which would look like the socket closing.
+    fn unfilled(&mut self) -> &mut [u8] {
+        if self.index == self.buf.len() {
+            self.buf.resize(self.buf.len() + 1024, 0);
+        }
+        &mut self.buf[self.index..]
+    }
+
+    fn state(&self) -> (usize, usize, usize) {
+        let buffered = &self.buf[..self.index];
+        let partial = match buffered.iter().rposition(|b| *b == b'\n') {
+            Some(pos) => buffered.len() - pos - 1,
+            None => buffered.len(),
+        };
+        (self.buf.len(), self.index, partial)
+    }
+
+    fn max_line(&self) -> usize {
+        self.max_line
+    }
+
+    fn set_max_line(&mut self, max_line: usize) {
+        self.max_line = max_line;
+    }
+
+    /// Takes the first line out of the buffer.
+    ///
+    /// ```
+    /// use chattery::{Frame, FrameResult, Framing};
+    ///
+    /// let mut frame = Frame::new();
+    /// let bytes = b"join rust\nmsg ru";
+    /// frame.unfilled()[..bytes.len()].copy_from_slice(bytes);
+    /// frame.update(bytes.len());
+    ///
+    /// assert!(matches!(frame.frame(), FrameResult::Complete(line) if line == b"join rust\n"));
+    /// // The rest of the second line hasn't arrived yet
+    /// assert!(matches!(frame.frame(), FrameResult::Incomplete));
+    /// ```
+    fn frame(&mut self) -> FrameResult {
+        // Find the position of the newline char if there is one...
+        let Some(pos) = self.buf[..self.index].iter().position(|b| *b == b'\n') else {
+            // ... otherwise wait for more, unless the line has grown too long
+            if self.index <= self.max_line {
+                return FrameResult::Incomplete;
+            }
+            self.index = 0;
+            self.buf = vec![0; 1024];
+            // A line that is already being dropped was reported the first time
+            if std::mem::replace(&mut self.discarding, true) {
+                return FrameResult::Incomplete;
+            }
+            return FrameResult::TooLong;
+        };
+
+        // Split the buffer at the position of the newline char.
+        // This will set self.buffer to be the value we want to return,
+        // and `let mut buf` will contain what we want to keep as self.buf,
+        // there fore...
+        let mut buf = self.buf.split_off(pos + 1);
+        // ... we need to swap self.buf with buf
+        std::mem::swap(&mut buf, &mut self.buf);
+        // Everything up to and including the newline is gone from self.buf
+        self.index -= pos + 1;
+
+        // Resize our inner buffer to fit another kb,
+        // without dropping any bytes that are still buffered
+        self.buf.resize(self.index.max(1024), 0);
+
+        // The tail end of a line that was too long
+        if std::mem::take(&mut self.discarding) {
+            return self.frame();
+        }
+        // Too long, but the newline came in the same read
+        if buf.len() > self.max_line {
+            return FrameResult::TooLong;
+        }
+
+        FrameResult::Complete(buf)
+    }
+}
+
+/// Messages prefixed with their length as a 4 byte big endian integer,
+/// so they can contain newlines or any other bytes.
+/// Replies from the server are still newline terminated.
+pub struct LengthFrame {
+    buf: Vec<u8>,
+    index: usize,
+    // What is left of a message that is too long, skipped as it comes in
+    skip: usize,
+    max_line: usize,
+}
+
+impl LengthFrame {
+    pub fn new() -> Self {
+        Self {
+            buf: vec![0; 1024],
+            index: 0,
+            skip: 0,
+            max_line: MAX_LINE,
+        }
+    }
+
+    // Drop the first `len` buffered bytes
+    fn consume(&mut self, len: usize) {
+        self.buf.copy_within(len..self.index, 0);
+        self.index -= len;
+    }
+}
+
+impl Default for LengthFrame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Framing for LengthFrame {
+    fn update(&mut self, bytes_read: usize) {
+        self.index += bytes_read;
+    }
+
+    fn unfilled(&mut self) -> &mut [u8] {
+        if self.index == self.buf.len() {
+            self.buf.resize(self.buf.len() + 1024, 0);
+        }
+        &mut self.buf[self.index..]
+    }
+
+    fn state(&self) -> (usize, usize, usize) {
+        (self.buf.len(), self.index, self.index)
+    }
+
+    fn max_line(&self) -> usize {
+        self.max_line
+    }
+
+    fn set_max_line(&mut self, max_line: usize) {
+        self.max_line = max_line;
+    }
+
+    fn frame(&mut self) -> FrameResult {
+        if self.skip > 0 {
+            let skipped = self.skip.min(self.index);
+            self.consume(skipped);
+            self.skip -= skipped;
+            if self.skip > 0 {
+                return FrameResult::Incomplete;
+            }
+        }
+
+        let Some(header) = self.buf[..self.index].first_chunk::<4>() else { return FrameResult::Incomplete };
+        let len = u32::from_be_bytes(*header) as usize;
+        if len > self.max_line {
+            self.consume(4);
+            self.skip = len;
+            return FrameResult::TooLong;
+        }
+        if self.index < 4 + len {
+            return FrameResult::Incomplete;
+        }
+
+        let mut payload = self.buf[4..4 + len].to_vec();
+        self.consume(4 + len);
+        // Everything after framing expects the newline a line would end with
+        payload.push(b'\n');
+        FrameResult::Complete(payload)
+    }
+}
+
+// -----------------------------------------------------------------------------
+//   - Commands -
+// -----------------------------------------------------------------------------
+// * join <room name>\n
+// * part <room name>\n
+// * msg <room name> <msg>\n
+// * multimsg <room name> <msg>\0<msg>\0<msg>\n
+// * broadcast <msg>\n
+// * ban <room name> <username>\n
+// * kick <room name> <username>\n
+// * setgreeting <room name> <greeting>\n
+// * watch <room name>\n
+// * unwatch <room name>\n
+// * mystats\n
+// * stats\n
+// * admin <token>\n
+// * config\n (admins only)
+// * link <room name> <host:port>\n (admins only)
+// * unlink <room name>\n (admins only)
+// * since <room name> <seq>\n
+// * react <room name> <seq> <emoji>\n
+// * export <room name>\n
+// * recall <n>\n (up to MAX_RECALL)
+// * schedule <room name> <seconds> <msg>\n (up to MAX_SCHEDULE_DELAY seconds)
+// * cancelschedule <id>\n
+// * schedules\n
+// * nick <username>\n
+// * who <room name>\n
+// * pm <username> <msg>\n
+// * mute <username>\n
+// * unmute <username>\n
+// * list\n
+// * loadrooms\n (the rooms the username was in when it last disconnected, within `session_ttl`)
+// * me <room name> <action>\n
+// * topic <room name> [<topic>]\n
+// * typing <room name>\n
+// * switch <room name> <room name>\n
+// * setmotd <room name> <motd>\n
+// * clearmotd <room name>\n
+// * roster <room name>\n
+// * roomcaps <room name>\n
+// * verbose on|off\n
+// * echo on|off\n
+// * invisible on|off\n
+// * isonline <username>\n
+// * frametest\n
+// * buflimit [<bytes>]\n (admins only)
+// * help\n
+// * quit\n
+//
+// Room names are case-insensitive, see `Command::canonicalize_rooms`
+#[derive(Debug)]
+pub enum Command {
+    Join(Room),
+    Part(Room),
+    Msg { room: Room, msg: String },
+    MultiMsg { room: Room, msgs: Vec<String> },
+    // To every room the user is in
+    Broadcast(String),
+    Ban { room: Room, username: String },
+    Kick { room: Room, username: String },
+    SetGreeting { room: Room, greeting: String },
+    Watch(Room),
+    Unwatch(Room),
+    MyStats,
+    Stats,
+    Admin(String),
+    // The settings the server is running with
+    Config,
+    Link { room: Room, addr: String },
+    Unlink(Room),
+    Schedule { room: Room, delay: u64, msg: String },
+    // The messages in the room's history after this sequence number
+    Since { room: Room, seq: u64 },
+    // To a message in the room's history
+    React { room: Room, seq: u64, emoji: String },
+    // The room's whole history, for members
+    Export(Room),
+    // The last messages from all the rooms the user is in
+    Recall(usize),
+    CancelSchedule(usize),
+    Typing(Room),
+    Switch { from: Room, to: Room },
+    SetMotd { room: Room, motd: String },
+    ClearMotd(Room),
+    Roster(Room),
+    // The room's limits and which of its settings are in use
+    RoomCaps(Room),
+    Verbose(bool),
+    Echo(bool),
+    Invisible(bool),
+    // Without saying where they are
+    IsOnline(String),
+    FrameTest,
+    // Show or set the longest line this connection may send
+    BufLimit(Option<usize>),
+    Schedules,
+    Nick(String),
+    Who(Room),
+    Pm { to: String, msg: String },
+    Mute(String),
+    Unmute(String),
+    List,
+    // Rejoin the rooms the username was in when it last disconnected
+    LoadRooms,
+    Me { room: Room, action: String },
+    // None shows the current topic
+    Topic { room: Room, topic: Option<String> },
+    Help,
+    Quit,
+}
+
+impl Command {
+    // Is this the first word of a command
+    fn is_verb(word: &[u8]) -> bool {
+        matches!(
+            word,
+            b"join"
+                | b"part"
+                | b"msg"
+                | b"multimsg"
+                | b"broadcast"
+                | b"ban"
+                | b"kick"
+                | b"setgreeting"
+                | b"watch"
+                | b"unwatch"
+                | b"mystats"
+                | b"stats"
+                | b"admin"
+                | b"config"
+                | b"link"
+                | b"unlink"
+                | b"schedule"
+                | b"since"
+                | b"react"
+                | b"export"
+                | b"recall"
+                | b"cancelschedule"
+                | b"typing"
+                | b"switch"
+                | b"setmotd"
+                | b"clearmotd"
+                | b"roster"
+                | b"roomcaps"
+                | b"verbose"
+                | b"echo"
+                | b"invisible"
+                | b"isonline"
+                | b"frametest"
+                | b"buflimit"
+                | b"schedules"
+                | b"nick"
+                | b"who"
+                | b"pm"
+                | b"mute"
+                | b"unmute"
+                | b"list"
+                | b"loadrooms"
+                | b"me"
+                | b"topic"
+                | b"help"
+                | b"quit"
+        )
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Join(_) => "join",
+            Self::Part(_) => "part",
+            Self::Msg { .. } => "msg",
+            Self::MultiMsg { .. } => "multimsg",
+            Self::Broadcast(_) => "broadcast",
+            Self::Ban { .. } => "ban",
+            Self::Kick { .. } => "kick",
+            Self::SetGreeting { .. } => "setgreeting",
+            Self::Watch(_) => "watch",
+            Self::Unwatch(_) => "unwatch",
+            Self::MyStats => "mystats",
+            Self::Stats => "stats",
+            Self::Admin(_) => "admin",
+            Self::Config => "config",
+            Self::Link { .. } => "link",
+            Self::Unlink(_) => "unlink",
+            Self::Schedule { .. } => "schedule",
+            Self::Since { .. } => "since",
+            Self::React { .. } => "react",
+            Self::Export(_) => "export",
+            Self::Recall(_) => "recall",
+            Self::CancelSchedule(_) => "cancelschedule",
+            Self::Typing(_) => "typing",
+            Self::Switch { .. } => "switch",
+            Self::SetMotd { .. } => "setmotd",
+            Self::ClearMotd(_) => "clearmotd",
+            Self::Roster(_) => "roster",
+            Self::RoomCaps(_) => "roomcaps",
+            Self::Verbose(_) => "verbose",
+            Self::Echo(_) => "echo",
+            Self::Invisible(_) => "invisible",
+            Self::IsOnline(_) => "isonline",
+            Self::FrameTest => "frametest",
+            Self::BufLimit(_) => "buflimit",
+            Self::Schedules => "schedules",
+            Self::Nick(_) => "nick",
+            Self::Who(_) => "who",
+            Self::Pm { .. } => "pm",
+            Self::Mute(_) => "mute",
+            Self::Unmute(_) => "unmute",
+            Self::List => "list",
+            Self::LoadRooms => "loadrooms",
+            Self::Me { .. } => "me",
+            Self::Topic { .. } => "topic",
+            Self::Help => "help",
+            Self::Quit => "quit",
+        }
+    }
+
+    /// Parses one framed line, trailing newline optional.
+    ///
+    /// ```
+    /// use chattery::{Command, ParseError};
+    ///
+    /// let command = Command::parse(b"msg rust hello there\n".to_vec()).unwrap();
+    /// assert!(matches!(command, Command::Msg { room, msg } if room == "rust" && msg == "hello there"));
+    ///
+    /// let err = Command::parse(b"join\n".to_vec()).unwrap_err();
+    /// assert!(matches!(err, ParseError::MissingRoom));
+    /// ```
+    pub fn parse(bytes: Vec<u8>) -> Result<Self, ParseError> {
+        // Convert bytes to String
+        let command = String::from_utf8(bytes).map_err(|_| ParseError::NotUtf8)?;
+        // Remove the trailing newline (if there is one, so short input can't
+        // cost us the last char of the command instead)
+        let command = command.strip_suffix('\n').unwrap_or(&command);
+
+        // Commands without any arguments
+        match command {
+            "mystats" => return Ok(Self::MyStats),
+            "stats" => return Ok(Self::Stats),
+            "config" => return Ok(Self::Config),
+            "frametest" => return Ok(Self::FrameTest),
+            "buflimit" => return Ok(Self::BufLimit(None)),
+            "schedules" => return Ok(Self::Schedules),
+            "list" => return Ok(Self::List),
+            "loadrooms" => return Ok(Self::LoadRooms),
+            "help" => return Ok(Self::Help),
+            "quit" => return Ok(Self::Quit),
+            _ => {}
+        }
+
+        // `split_once` never yields out of bounds halves,
+        // unlike the index arithmetic with `find` + `split_off`
+        let Some((command, rest)) = command.split_once(' ') else {
+            return Err(match command {
+                "nick" | "pm" | "mute" | "unmute" | "admin" | "isonline" | "verbose" | "echo" | "invisible" | "recall" | "cancelschedule" => {
+                    ParseError::MissingArgument
+                }
+                "broadcast" => ParseError::MissingMessage,
+                _ if Self::is_verb(command.as_bytes()) => ParseError::MissingRoom,
+                _ => ParseError::UnknownCommand(command.into()),
+            });
+        };
+
+        match command {
+            "join" | "part" | "watch" | "unwatch" | "unlink" | "typing" | "clearmotd" | "roster" | "roomcaps" | "who" | "export"
+                if rest.is_empty() =>
+            {
+                Err(ParseError::MissingRoom)
+            }
+            "join" | "part" | "watch" | "unwatch" | "unlink" | "typing" | "clearmotd" | "roster" | "roomcaps" | "who" | "export"
+                if rest.contains(' ') =>
+            {
+                Err(ParseError::RoomHasWhitespace)
+            }
+
+            "join" => Ok(Self::Join(rest.into())),
+            "part" => Ok(Self::Part(rest.into())),
+            "watch" => Ok(Self::Watch(rest.into())),
+            "unwatch" => Ok(Self::Unwatch(rest.into())),
+            "unlink" => Ok(Self::Unlink(rest.into())),
+            "typing" => Ok(Self::Typing(rest.into())),
+            "clearmotd" => Ok(Self::ClearMotd(rest.into())),
+            "roster" => Ok(Self::Roster(rest.into())),
+            "roomcaps" => Ok(Self::RoomCaps(rest.into())),
+            "who" => Ok(Self::Who(rest.into())),
+            "export" => Ok(Self::Export(rest.into())),
+            "nick" | "mute" | "unmute" | "admin" | "isonline" => {
+                if rest.is_empty() || rest.contains(' ') {
+                    return Err(ParseError::InvalidArgument);
+                }
+                match command {
+                    "nick" => Ok(Self::Nick(rest.into())),
+                    "mute" => Ok(Self::Mute(rest.into())),
+                    "unmute" => Ok(Self::Unmute(rest.into())),
+                    "admin" => Ok(Self::Admin(rest.into())),
+                    _ => Ok(Self::IsOnline(rest.into())),
+                }
+            }
+            "recall" => match rest.parse() {
+                Ok(0) | Err(_) => Err(ParseError::InvalidArgument),
+                Ok(n) => Ok(Self::Recall(n)),
+            },
+            "buflimit" => match rest.parse() {
+                Ok(limit @ 1..=MAX_BUFLIMIT) => Ok(Self::BufLimit(Some(limit))),
+                _ => Err(ParseError::InvalidArgument),
+            },
+            "verbose" => match rest {
+                "on" => Ok(Self::Verbose(true)),
+                "off" => Ok(Self::Verbose(false)),
+                _ => Err(ParseError::InvalidArgument),
+            },
+            "echo" => match rest {
+                "on" => Ok(Self::Echo(true)),
+                "off" => Ok(Self::Echo(false)),
+                _ => Err(ParseError::InvalidArgument),
+            },
+            "invisible" => match rest {
+                "on" => Ok(Self::Invisible(true)),
+                "off" => Ok(Self::Invisible(false)),
+                _ => Err(ParseError::InvalidArgument),
+            },
+            "msg" => {
+                let (room, msg) = Self::split_room(rest)?;
+                Ok(Self::Msg { room, msg: msg.into() })
+            }
+            "broadcast" if rest.is_empty() => Err(ParseError::MissingMessage),
+            "broadcast" => Ok(Self::Broadcast(rest.into())),
+            "multimsg" => {
+                let (room, msgs) = Self::split_room(rest)?;
+                // Messages are separated by a null byte as they can't contain a newline
+                let msgs = msgs.split('\0').filter(|msg| !msg.is_empty()).map(String::from).collect();
+                Ok(Self::MultiMsg { room, msgs })
+            }
+            "ban" | "kick" => {
+                let (room, username) = Self::split_room(rest)?;
+                // Usernames can't be empty or contain whitespace
+                if username.is_empty() || username.contains(' ') {
+                    return Err(ParseError::InvalidArgument);
+                }
+                match command {
+                    "ban" => Ok(Self::Ban { room, username: username.into() }),
+                    _ => Ok(Self::Kick { room, username: username.into() }),
+                }
+            }
+            "setgreeting" => {
+                let (room, greeting) = Self::split_room(rest)?;
+                if greeting.is_empty() {
+                    return Err(ParseError::MissingMessage);
+                }
+                Ok(Self::SetGreeting { room, greeting: greeting.into() })
+            }
+            "setmotd" => {
+                let (room, motd) = Self::split_room(rest)?;
+                if motd.is_empty() {
+                    return Err(ParseError::MissingMessage);
+                }
+                Ok(Self::SetMotd { room, motd: motd.into() })
+            }
+            "link" => {
+                let (room, addr) = Self::split_room(rest)?;
+                if addr.is_empty() || addr.contains(' ') {
+                    return Err(ParseError::InvalidArgument);
+                }
+                Ok(Self::Link { room, addr: addr.into() })
+            }
+            "schedule" => {
+                let (room, rest) = Self::split_room(rest)?;
+                let (delay, msg) = rest.split_once(' ').ok_or(ParseError::MissingMessage)?;
+                let delay = delay.parse().ok().filter(|delay| *delay <= MAX_SCHEDULE_DELAY).ok_or(ParseError::InvalidArgument)?;
+                Ok(Self::Schedule { room, delay, msg: msg.into() })
+            }
+            "since" => {
+                let (room, seq) = Self::split_room(rest)?;
+                let seq = seq.parse().map_err(|_| ParseError::InvalidArgument)?;
+                Ok(Self::Since { room, seq })
+            }
+            "react" => {
+                let (room, rest) = Self::split_room(rest)?;
+                let (seq, emoji) = rest.split_once(' ').ok_or(ParseError::MissingArgument)?;
+                let seq = seq.parse().map_err(|_| ParseError::InvalidArgument)?;
+                if emoji.is_empty() || emoji.contains(char::is_whitespace) || emoji.chars().count() > MAX_EMOJI_LEN {
+                    return Err(ParseError::InvalidArgument);
+                }
+                Ok(Self::React { room, seq, emoji: emoji.into() })
+            }
+            "me" => {
+                let (room, action) = Self::split_room(rest)?;
+                Ok(Self::Me { room, action: action.into() })
+            }
+            "topic" => match rest.split_once(' ') {
+                None if rest.is_empty() => Err(ParseError::MissingRoom),
+                None => Ok(Self::Topic { room: rest.into(), topic: None }),
+                Some(("", _)) => Err(ParseError::MissingRoom),
+                Some((_, "")) => Err(ParseError::MissingMessage),
+                Some((room, topic)) => Ok(Self::Topic { room: room.into(), topic: Some(topic.into()) }),
+            },
+            "pm" => {
+                let (to, msg) = Self::split_room(rest)?;
+                Ok(Self::Pm { to, msg: msg.into() })
+            }
+            "switch" => {
+                // Both ways `split_room` can fail mean a room is missing here
+                let (from, to) = Self::split_room(rest).map_err(|_| ParseError::MissingRoom)?;
+                if to.is_empty() {
+                    return Err(ParseError::MissingRoom);
+                }
+                if to.contains(' ') {
+                    return Err(ParseError::RoomHasWhitespace);
+                }
+                Ok(Self::Switch { from, to: to.into() })
+            }
+            "cancelschedule" => Ok(Self::CancelSchedule(rest.parse().map_err(|_| ParseError::InvalidArgument)?)),
+            _ => Err(ParseError::UnknownCommand(command.into())),
+        }
+    }
+
+    // Split `<room name> <rest>`, the room name can't be empty
+    fn split_room(input: &str) -> Result<(Room, &str), ParseError> {
+        let (room, rest) = input.split_once(' ').ok_or(ParseError::MissingMessage)?;
+        if room.is_empty() {
+            return Err(ParseError::MissingRoom);
+        }
+        Ok((room.into(), rest))
+    }
+
+    // Room names are case-insensitive: rooms are looked up by the lowercased name.
+    // Returns the name as typed of the room being joined, if any,
+    // which is what the room is shown as if this creates it.
+    fn canonicalize_rooms(&mut self) -> Option<Room> {
+        let display = match self {
+            Self::Join(room) | Self::Switch { to: room, .. } => Some(room.clone()),
+            _ => None,
+        };
+        match self {
+            Self::Switch { from, to } => {
+                *from = canonical_room(from);
+                *to = canonical_room(to);
+            }
+            Self::Join(room)
+            | Self::Part(room)
+            | Self::Watch(room)
+            | Self::Unwatch(room)
+            | Self::Unlink(room)
+            | Self::Typing(room)
+            | Self::ClearMotd(room)
+            | Self::Roster(room)
+            | Self::RoomCaps(room)
+            | Self::Who(room)
+            | Self::Export(room)
+            | Self::Msg { room, .. }
+            | Self::MultiMsg { room, .. }
+            | Self::Ban { room, .. }
+            | Self::Kick { room, .. }
+            | Self::SetGreeting { room, .. }
+            | Self::Link { room, .. }
+            | Self::Schedule { room, .. }
+            | Self::Since { room, .. }
+            | Self::React { room, .. }
+            | Self::SetMotd { room, .. }
+            | Self::Me { room, .. }
+            | Self::Topic { room, .. } => *room = canonical_room(room),
+            _ => {}
+        }
+        display
+    }
+}
+
+fn canonical_room(name: &str) -> Room {
+    name.to_lowercase()
+}
+
+// Why a line couldn't be parsed into a `Command`, sent back to the user
+#[derive(Debug)]
+pub enum ParseError {
+    NotUtf8,
+    UnknownCommand(String),
+    MissingRoom,
+    MissingMessage,
+    MissingArgument,
+    InvalidArgument,
+    RoomHasWhitespace,
+    InvalidJson,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotUtf8 => write!(f, "error: not valid UTF-8"),
+            Self::UnknownCommand(command) => write!(f, "error: unknown command '{command}'"),
+            Self::MissingRoom => write!(f, "error: missing room name"),
+            Self::MissingMessage => write!(f, "error: missing message"),
+            Self::MissingArgument => write!(f, "error: missing argument"),
+            Self::InvalidArgument => write!(f, "error: invalid argument"),
+            Self::RoomHasWhitespace => write!(f, "error: room names can't contain whitespace"),
+            Self::InvalidJson => write!(f, "error: not a valid json command"),
+        }
+    }
+}
+
+impl ParseError {
+    /// The reason code sent to `mode raw` connections
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotUtf8 => "notutf8",
+            Self::UnknownCommand(_) => "unknowncommand",
+            Self::MissingRoom => "missingroom",
+            Self::MissingMessage => "missingmessage",
+            Self::MissingArgument => "missingargument",
+            Self::InvalidArgument => "invalidargument",
+            Self::RoomHasWhitespace => "roomwhitespace",
+            Self::InvalidJson => "invalidjson",
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//   - JSON lines -
+//   With `--json` a client sends `{"cmd":"msg","room":"general","text":"hi"}`
+//   instead of `msg general hi`, and gets `{"type":"msg","room":"general","from":"alice","text":"hi"}`
+//   instead of `alice: hi`. Commands are turned into the line they stand for,
+//   so usernames and `auth` work the same, and events are made from the lines
+//   just before they are written. Fields other than `text` must be one word.
+// -----------------------------------------------------------------------------
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+enum WireCommand {
+    // Picks the username, in place of the first line
+    Login { name: String },
+    Auth { token: String },
+    Join { room: Room },
+    Part { room: Room },
+    Msg { room: Room, text: String },
+    Me { room: Room, text: String },
+    Pm { to: String, text: String },
+    Nick { name: String },
+    Who { room: Room },
+    Topic { room: Room, text: Option<String> },
+    Watch { room: Room },
+    Unwatch { room: Room },
+    List,
+    Help,
+    Quit,
+    Ping,
+    Pong,
+    Resume { token: String },
+}
+
+impl WireCommand {
+    // The newline terminated line this JSON command stands for
+    fn to_line(payload: &[u8]) -> Result<Vec<u8>, ParseError> {
+        let command: Self = serde_json::from_slice(payload).map_err(|_| ParseError::InvalidJson)?;
+        command.check_words()?;
+        let line = match command {
+            Self::Login { name } => name,
+            Self::Auth { token } => format!("auth {token}"),
+            Self::Join { room } => format!("join {room}"),
+            Self::Part { room } => format!("part {room}"),
+            Self::Msg { room, text } => format!("msg {room} {text}"),
+            Self::Me { room, text } => format!("me {room} {text}"),
+            Self::Pm { to, text } => format!("pm {to} {text}"),
+            Self::Nick { name } => format!("nick {name}"),
+            Self::Who { room } => format!("who {room}"),
+            Self::Topic { room, text: None } => format!("topic {room}"),
+            Self::Topic { room, text: Some(text) } => format!("topic {room} {text}"),
+            Self::Watch { room } => format!("watch {room}"),
+            Self::Unwatch { room } => format!("unwatch {room}"),
+            Self::List => "list".into(),
+            Self::Help => "help".into(),
+            Self::Quit => "quit".into(),
+            Self::Ping => "ping".into(),
+            Self::Pong => "pong".into(),
+            Self::Resume { token } => format!("resume {token}"),
+        };
+        // A newline in any of the fields would smuggle in a second line
+        if line.contains('\n') {
+            return Err(ParseError::InvalidArgument);
+        }
+        let mut line = line.into_bytes();
+        line.push(b'\n');
+        Ok(line)
+    }
+
+    // Everything but `text` is a single word of the line, so a space in it
+    // would shift the rest: `"room":"a b"` would post to room `a`
+    fn check_words(&self) -> Result<(), ParseError> {
+        let (room, word) = match self {
+            Self::Join { room }
+            | Self::Part { room }
+            | Self::Msg { room, .. }
+            | Self::Me { room, .. }
+            | Self::Who { room }
+            | Self::Topic { room, .. }
+            | Self::Watch { room }
+            | Self::Unwatch { room } => (Some(room), None),
+            Self::Login { name } | Self::Nick { name } => (None, Some(name)),
+            Self::Auth { token } | Self::Resume { token } => (None, Some(token)),
+            Self::Pm { to, .. } => (None, Some(to)),
+            Self::List | Self::Help | Self::Quit | Self::Ping | Self::Pong => (None, None),
+        };
+        match (room, word) {
+            (Some(room), _) if room.is_empty() => Err(ParseError::MissingRoom),
+            (Some(room), _) if room.contains(char::is_whitespace) => Err(ParseError::RoomHasWhitespace),
+            (_, Some(word)) if word.is_empty() => Err(ParseError::MissingArgument),
+            (_, Some(word)) if word.contains(char::is_whitespace) => Err(ParseError::InvalidArgument),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum WireEvent<'a> {
+    Msg {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        time: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        room: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        from: &'a str,
+        text: &'a str,
+    },
+    Pm {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        time: Option<&'a str>,
+        from: &'a str,
+        text: &'a str,
+    },
+    Notice {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        room: Option<&'a str>,
+        text: &'a str,
+    },
+    Error { text: &'a str },
+}
+
+impl<'a> WireEvent<'a> {
+    // Usernames can't contain whitespace, so `<name>: ` can only be a chat message.
+    // `room` is the room the line was sent to, if it came from one,
+    // `seq` its number in the room's history.
+    fn from_line(line: &'a str, room: Option<&'a str>, seq: Option<u64>) -> Self {
+        if let Some(text) = line.strip_prefix("error: ") {
+            return Self::Error { text };
+        }
+        if let Some(text) = line.strip_prefix("* ") {
+            return Self::Notice { room, text };
+        }
+        // `[12:34:05] ` in front, with `--timestamps`
+        let time = line.get(..11).filter(|stamp| {
+            stamp.starts_with('[') && stamp.ends_with("] ") && stamp[1..9].bytes().all(|b| b.is_ascii_digit() || b == b':')
+        });
+        let (time, rest) = match time {
+            Some(stamp) => (Some(&stamp[1..9]), &line[11..]),
+            None => (None, line),
+        };
+        if let Some((from, text)) = rest.strip_prefix("[pm] ").and_then(|pm| pm.split_once(": ")) {
+            return Self::Pm { time, from, text };
+        }
+        match rest.split_once(": ") {
+            Some((from, text)) if !from.contains(char::is_whitespace) => Self::Msg { time, room, seq, from, text },
+            _ => Self::Notice { room, text: line },
+        }
+    }
+
+    // Every line of an outgoing message as a JSON event
+    fn encode(message: &Outgoing) -> Vec<u8> {
+        let (room, seq) = (message.room.as_deref(), message.seq);
+        let message = String::from_utf8_lossy(&message.bytes);
+        let mut encoded = Vec::with_capacity(message.len() * 2);
+        for line in message.lines() {
+            // Serializing borrowed strings into a Vec can't fail
+            let _ = serde_json::to_writer(&mut encoded, &WireEvent::from_line(line, room, seq));
+            encoded.push(b'\n');
+        }
+        encoded
+    }
+}
+
+// Both are sent on connect, the help again for `help`
+const BANNER: &str = "* welcome to chattery\n";
+const HELP: &str = "\
+* join <room>, part <room>, switch <from> <to>, list, who <room>, topic <room> [topic]
+* msg <room> <msg>, me <room> <action>, pm <user> <msg>, broadcast <msg>
+* nick <name>, mute <user>, unmute <user>, mystats, stats, help, quit
+";
+
+// -----------------------------------------------------------------------------
+//   - Parse -
+// -----------------------------------------------------------------------------
+// input -> splitn(' ', 3)
+
+// -----------------------------------------------------------------------------
+//   - History log -
+//   Every chat message as a line of JSON, in one file for all rooms.
+//   On startup the last `history_len` messages of each room are read back
+//   and handed to the room when it's next created.
+//   The file is written by a blocking task of its own, so the rooms task never
+//   waits on the disk. Once it grows past HISTORY_FILE_MAX_SIZE it is moved to
+//   `<path>.1` (replacing the one before) and a new file is started, so only
+//   those two are ever read back.
+// -----------------------------------------------------------------------------
+const HISTORY_FILE_MAX_SIZE: u64 = 16 * 1024 * 1024;
+// Messages waiting to be written before new ones are dropped from the log
+const HISTORY_LOG_CAPACITY: usize = 1_000;
+
+#[derive(Serialize, Deserialize)]
+struct LoggedMessage {
+    // Seconds since the unix epoch
+    time: u64,
+    room: Room,
+    line: String,
+}
+
+struct HistoryLog {
+    writer: mpsc::Sender<LoggedMessage>,
+    restored: HashMap<Room, VecDeque<Arc<[u8]>>>,
+}
+
+impl HistoryLog {
+    async fn open(path: &str, history_len: usize, max_size: u64) -> std::io::Result<Self> {
+        let path = PathBuf::from(path);
+        let (restored, file) = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || {
+                let restored = Self::read(&path, history_len)?;
+                let file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+                Ok::<_, std::io::Error>((restored, file))
+            }
+        })
+        .await??;
+
+        let (writer, receiver) = mpsc::channel(HISTORY_LOG_CAPACITY);
+        tokio::task::spawn_blocking(move || Self::write(path, file, max_size, receiver));
+        Ok(Self { writer, restored })
+    }
+
+    // The rotated file first, it has the older messages
+    fn read(path: &Path, history_len: usize) -> std::io::Result<HashMap<Room, VecDeque<Arc<[u8]>>>> {
+        let mut restored: HashMap<Room, VecDeque<Arc<[u8]>>> = HashMap::new();
+        for path in [rotated_path(path), path.to_path_buf()] {
+            let contents = match std::fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for line in contents.lines().filter(|_| history_len > 0) {
+                // A line cut short by a crash is skipped
+                let Ok(message) = serde_json::from_str::<LoggedMessage>(line) else { continue };
+                let history = restored.entry(canonical_room(&message.room)).or_default();
+                if history.len() == history_len {
+                    history.pop_front();
+                }
+                history.push_back(format!("{}\n", message.line).into_bytes().into());
+            }
+        }
+        Ok(restored)
+    }
+
+    // Runs until the rooms task is gone.
+    // A failed write loses the message from the log, but not from the room
+    fn write(path: PathBuf, mut file: std::fs::File, max_size: u64, mut receiver: Receiver<LoggedMessage>) {
+        let mut size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+        while let Some(message) = receiver.blocking_recv() {
+            let Ok(mut line) = serde_json::to_vec(&message) else { continue };
+            line.push(b'\n');
+
+            if size > 0 && size + line.len() as u64 > max_size {
+                let rotated = std::fs::rename(&path, rotated_path(&path))
+                    .and_then(|_| std::fs::OpenOptions::new().create(true).append(true).open(&path));
+                match rotated {
+                    Ok(new_file) => {
+                        file = new_file;
+                        size = 0;
+                    }
+                    Err(e) => warn!("failed to rotate history log: {e}"),
+                }
+            }
+
+            match file.write_all(&line) {
+                Ok(()) => size += line.len() as u64,
+                Err(e) => warn!("failed to write to history log: {e}"),
+            }
+        }
+    }
+
+    // Never waits, if the writer is that far behind the message is left out of the log
+    fn append(&mut self, room: &str, bytes: &[u8]) {
+        let message = LoggedMessage {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            room: room.into(),
+            line: String::from_utf8_lossy(bytes).trim_end_matches('\n').into(),
+        };
+        if self.writer.try_send(message).is_err() {
+            warn!("history log is behind, message not logged");
+        }
+    }
+
+    // What was read back for a room, only the first time it's asked for
+    fn restore(&mut self, room: &str) -> VecDeque<Arc<[u8]>> {
+        self.restored.remove(room).unwrap_or_default()
+    }
+}
+
+// `<path>.1`
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+// -----------------------------------------------------------------------------
+//   - Saved rooms -
+//   With `rooms_file` the rooms task writes every room's name, topic, motd
+//   and greeting, and the bans, to a JSON file when the server shuts down.
+//   On startup the bans apply straight away, and like the history log
+//   a room gets what was saved for it when it's next created.
+//   Operators are connections, so they aren't saved.
+// -----------------------------------------------------------------------------
+#[derive(Default, Serialize, Deserialize)]
+struct SavedRooms {
+    rooms: Vec<SavedRoom>,
+    // Usernames by room
+    bans: HashMap<Room, Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedRoom {
+    // As shown, not lowercased
+    name: Room,
+    topic: Option<String>,
+    motd: Option<String>,
+    greeting: Option<String>,
+}
+
+impl SavedRooms {
+    // Nothing is saved yet if the file doesn't exist
+    async fn load(path: &str) -> std::io::Result<Self> {
+        match tokio::fs::read(path).await {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Written next to the file and then moved over it,
+    // so a crash halfway through leaves the last save intact
+    async fn save(&self, path: &str) -> std::io::Result<()> {
+        let partial = format!("{path}.partial");
+        tokio::fs::write(&partial, serde_json::to_vec_pretty(self)?).await?;
+        tokio::fs::rename(&partial, path).await
+    }
+}
+
+// -----------------------------------------------------------------------------
+//   - Rooms -
+//   The user who creates a room becomes the operator of that room.
+//   Bans are stored by username outside of the room itself,
+//   so they outlive both the room and the banned user's connection.
+// -----------------------------------------------------------------------------
+// Messages sent to a room, with the id of the user who sent it
+// (None for notices that go to everyone) and the room they were sent to
+type RoomMessage = (Option<usize>, Outgoing);
+
+// A chat message kept in a room's history
+struct HistoryEntry {
+    // Counts up from 1 in each room, so clients can ask for what they missed
+    // with `since`
+    seq: u64,
+    // Across all rooms, see `ServerState::next_post`. Restored messages come before the rest.
+    posted: usize,
+    line: StoredLine,
+}
+
+// How a history message is kept. With `history_compress` long ones are
+// deflated, trading the time to inflate them on every replay for memory.
+enum StoredLine {
+    Plain(Arc<[u8]>),
+    Deflated(Box<[u8]>),
+}
+
+impl StoredLine {
+    fn new(bytes: Arc<[u8]>, compress: Option<usize>) -> Self {
+        if compress.is_none_or(|min_len| bytes.len() < min_len) {
+            return Self::Plain(bytes);
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        // Writing to a Vec can't fail
+        let deflated = encoder.write_all(&bytes).and_then(|_| encoder.finish());
+        match deflated {
+            // Not everything gets smaller
+            Ok(deflated) if deflated.len() < bytes.len() => Self::Deflated(deflated.into()),
+            _ => Self::Plain(bytes),
+        }
+    }
+
+    fn bytes(&self) -> Arc<[u8]> {
+        match self {
+            Self::Plain(bytes) => bytes.clone(),
+            Self::Deflated(deflated) => {
+                let mut bytes = Vec::new();
+                // Inflating what was deflated above can't fail
+                if let Err(e) = DeflateDecoder::new(&deflated[..]).read_to_end(&mut bytes) {
+                    warn!("failed to inflate a history message: {e}");
+                }
+                bytes.into()
+            }
+        }
+    }
+}
+
+// A message from a room's history sent again on request, marked with where
+// it came from: `[<room> #<seq>] <line>`. JSON events have fields for that.
+fn replay(config: &Config, room: &str, entry: &HistoryEntry) -> Outgoing {
+    let line = entry.line.bytes();
+    let bytes = match config.json {
+        true => line,
+        false => [format!("[{room} #{}] ", entry.seq).as_bytes(), &line].concat().into(),
+    };
+    Outgoing { room: Some(room.into()), seq: Some(entry.seq), bytes }
+}
+
+// Reactions are short, an emoji may take a few chars
+const MAX_EMOJI_LEN: usize = 8;
+// Different emoji on one message
+const MAX_REACTIONS: usize = 32;
+// Messages sent back for `recall`, whatever the user asks for
+const MAX_RECALL: usize = 100;
+
+// Messages a room holds on to for a subscriber that is falling behind
+const ROOM_CAPACITY: usize = 100;
+// Every message is fanned out to each member, so one huge room would slow down everyone.
+// Joins past it are refused with `* room <room> is full [full]`, see `Rejection`
+const MAX_ROOM_MEMBERS: usize = 256;
+// Joins past it are refused with `* you have joined too many rooms [toomanyrooms]`
+const MAX_ROOMS_PER_USER: usize = 64;
+
+struct ChatRoom {
+    // The name as typed by whoever created the room,
+    // the key in the rooms map is the lowercased name
+    display_name: Room,
+    // By id, so leaving doesn't mean searching the whole room
+    members: HashMap<usize, Arc<Sender>>,
+    // Receive the room's messages without being a member
+    watchers: Vec<Arc<Sender>>,
+    // Every member and watcher has a task forwarding the room's messages
+    // to their connection, so a slow connection only holds up itself
+    channel: broadcast::Sender<RoomMessage>,
+    subscriptions: HashMap<usize, AbortHandle>,
+    operator: usize,
+    // Sent privately to everyone joining the room
+    motd: Option<String>,
+    greeting: Option<String>,
+    // Any member can set it, and it's shown to everyone joining
+    topic: Option<String>,
+    // Id of the watcher relaying this room to another server
+    link: Option<usize>,
+    // The last `history_len` chat messages, replayed to everyone joining
+    history: VecDeque<HistoryEntry>,
+    history_len: usize,
+    history_compress: Option<usize>,
+    // Of the next message posted
+    next_seq: u64,
+    // Who reacted with what, by sequence number.
+    // Only for messages still in the history.
+    reactions: HashMap<u64, HashMap<String, HashSet<String>>>,
+    // For the rooms created and messages posted counters
+    state: Arc<ServerState>,
+}
+
+impl ChatRoom {
+    fn new(display_name: Room, operator: usize, config: &Config, state: Arc<ServerState>) -> Self {
+        state.rooms_created.fetch_add(1, Ordering::Relaxed);
+        Self {
+            display_name,
+            members: HashMap::new(),
+            watchers: vec![],
+            channel: broadcast::channel(ROOM_CAPACITY).0,
+            subscriptions: HashMap::new(),
+            operator,
+            motd: None,
+            greeting: None,
+            topic: None,
+            link: None,
+            history: VecDeque::new(),
+            history_len: config.history_len,
+            history_compress: config.history_compress,
+            next_seq: 1,
+            reactions: HashMap::new(),
+            state,
+        }
+    }
+
+    // Everyone who should receive messages sent to the room
+    fn recipients(&self) -> impl Iterator<Item = &Arc<Sender>> {
+        self.members.values().chain(&self.watchers)
+    }
+
+    // Start forwarding the room's messages to a new member or watcher
+    fn subscribe(&mut self, room_name: &str, sender: &Arc<Sender>) {
+        let receiver = self.channel.subscribe();
+        let task = tokio::spawn(forward_room(room_name.into(), receiver, sender.clone()));
+        self.subscriptions.insert(sender.id, task.abort_handle());
+    }
+
+    fn unsubscribe(&mut self, id: usize) {
+        if let Some(handle) = self.subscriptions.remove(&id) {
+            handle.abort();
+        }
+    }
+
+    // Send a chat message to the room, keeping it in the history
+    fn post(&mut self, from: usize, bytes: Arc<[u8]>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if self.history_len > 0 {
+            if self.history.len() == self.history_len {
+                if let Some(oldest) = self.history.pop_front() {
+                    self.reactions.remove(&oldest.seq);
+                }
+            }
+            let posted = self.state.next_post.fetch_add(1, Ordering::Relaxed);
+            let line = StoredLine::new(bytes.clone(), self.history_compress);
+            self.history.push_back(HistoryEntry { seq, posted, line });
+        }
+        let message = Outgoing { room: Some(self.display_name.clone()), seq: Some(seq), bytes };
+        let _ = self.channel.send((Some(from), message));
+    }
+
+    // Messages read back from the history log, numbered from 1
+    fn restore(&mut self, history: VecDeque<Arc<[u8]>>) {
+        for bytes in history {
+            let line = StoredLine::new(bytes, self.history_compress);
+            self.history.push_back(HistoryEntry { seq: self.next_seq, posted: 0, line });
+            self.next_seq += 1;
+        }
+    }
+
+    // Nobody is listening if the room only has members who left,
+    // which is fine to ignore
+    fn broadcast(&self, from: Option<usize>, bytes: Arc<[u8]>) {
+        let message = Outgoing { room: Some(self.display_name.clone()), seq: None, bytes };
+        let _ = self.channel.send((from, message));
+    }
+}
+
+// Pass a room's messages on to one subscriber, leaving out the ones they sent.
+// Ends when they unsubscribe or the room goes away.
+async fn forward_room(room_name: Room, mut receiver: broadcast::Receiver<RoomMessage>, sender: Arc<Sender>) {
+    loop {
+        match receiver.recv().await {
+            Ok((from, _)) if from == Some(sender.id) && !sender.echo.load(Ordering::Relaxed) => {}
+            Ok((Some(from), _)) if sender.is_muted(from) => sender.notice(&format!("* message in {room_name} dropped: sender muted")),
+            // The connection is gone
+            Ok(_) if sender.inner.is_closed() => break,
+            Ok((_, message)) => sender.deliver(message),
+            // Too slow to keep up, the oldest messages were dropped
+            Err(RecvError::Lagged(missed)) => sender.reply(&format!("* missed {missed} messages in {room_name}")),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+// Format a chat message as `<username>: <msg>\n`
+// `chat_payload`, with the time in front if timestamps are on
+fn chat_line(config: &Config, username: &str, msg: &str) -> Arc<[u8]> {
+    let payload = chat_payload(username, msg);
+    if !config.timestamps {
+        return payload;
+    }
+    let mut stamped = timestamp((config.clock)()).into_bytes();
+    stamped.extend_from_slice(&payload);
+    stamped.into()
+}
+
+// `[hh:mm:ss] ` in UTC
+fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % (24 * 60 * 60);
+    format!("[{:02}:{:02}:{:02}] ", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn chat_payload(username: &str, msg: &str) -> Arc<[u8]> {
+    static SEPARATOR: &str = ": ";
+    static NL: u8 = b'\n';
+
+    let mut payload = Vec::<u8>::with_capacity(msg.len() + SEPARATOR.len() + username.len() + 1); // 1 = len of nl char
+    payload.extend(username.as_bytes());
+    payload.extend(SEPARATOR.as_bytes());
+    payload.extend(msg.as_bytes());
+    payload.push(NL);
+    payload.into()
+}
+
+// Add the user to a room, creating the room if it doesn't exist.
+// False if they were refused.
+#[allow(clippy::too_many_arguments)]
+fn join_room(
+    rooms: &mut HashMap<Room, ChatRoom>,
+    room_name: Room,
+    display_name: Option<Room>,
+    sender: Arc<Sender>,
+    config: &Config,
+    state: &Arc<ServerState>,
+    history_log: &mut Option<HistoryLog>,
+    saved_rooms: &mut HashMap<Room, SavedRoom>,
+) -> bool {
+    let joined = rooms.values().filter(|room| room.members.contains_key(&sender.id)).count();
+    if joined >= MAX_ROOMS_PER_USER && !rooms.get(&room_name).is_some_and(|room| room.members.contains_key(&sender.id)) {
+        sender.reject(Rejection::TooManyRooms);
+        return false;
+    }
+    let room = rooms.entry(room_name.clone()).or_insert_with(|| {
+        let saved = saved_rooms.remove(&room_name);
+        let display_name = saved.as_ref().map(|saved| saved.name.clone()).or(display_name);
+        let mut room = ChatRoom::new(display_name.unwrap_or_else(|| room_name.clone()), sender.id, config, state.clone());
+        if let Some(log) = history_log {
+            room.restore(log.restore(&room_name));
+        }
+        if let Some(saved) = saved {
+            room.topic = saved.topic;
+            room.motd = saved.motd;
+            room.greeting = saved.greeting;
+        }
+        room
+    });
+    if room.members.contains_key(&sender.id) {
+        sender.reject(Rejection::AlreadyJoined(&room_name));
+        return false;
+    }
+    if room.members.len() >= MAX_ROOM_MEMBERS {
+        sender.reject(Rejection::RoomFull(&room_name));
+        return false;
+    }
+    if let Some(topic) = &room.topic {
+        sender.reply(&format!("* topic for {room_name}: {topic}"));
+    }
+    if let Some(motd) = &room.motd {
+        sender.reply(&format!("* motd: {motd}"));
+    }
+    if let Some(greeting) = &room.greeting {
+        sender.reply(greeting);
+    }
+    // History goes out before the user subscribes, so nothing is sent twice
+    for entry in &room.history {
+        sender.deliver(Outgoing { room: Some(room.display_name.clone()), seq: Some(entry.seq), bytes: entry.line.bytes() });
+    }
+    // Members get messages anyway, so stop watching
+    room.watchers.retain(|s| s != &sender);
+    room.unsubscribe(sender.id);
+    // Everyone already in the room is told, before the user subscribes
+    room_notice(room, format!("* {} joined {room_name}\n", sender.username));
+    room.subscribe(&room_name, &sender);
+    info!("conn {} joined room {room_name}", sender.id);
+    room.members.insert(sender.id, sender);
+    true
+}
+
+// Send a notice to everyone in the room
+fn room_notice(room: &ChatRoom, notice: String) {
+    room.broadcast(None, notice.into_bytes().into());
+}
+
+// Remove the user from a room
+fn part_room(rooms: &mut HashMap<Room, ChatRoom>, room_name: &str, sender: &Sender) {
+    let Some(room) = rooms.get_mut(room_name) else { return };
+    if room.members.remove(&sender.id).is_none() {
+        return;
+    }
+    room.unsubscribe(sender.id);
+    room_notice(room, format!("* {} left {room_name}\n", sender.username));
+    info!("conn {} left room {room_name}", sender.id);
+    // If the room is empty after the last user left
+    // then remove the room (watchers don't keep a room alive)
+    if room.members.is_empty() {
+        rooms.remove(room_name);
+        debug!("room {room_name} is empty, removing");
+    }
+}
+
+const MAX_USERNAME_LEN: usize = 32;
+// Max chars in a single chat message
+const MAX_MESSAGE_LEN: usize = 1000;
+
+// Leading and trailing whitespace is trimmed off,
+// anything else that isn't a plain name is rejected
+fn validate_username(username: &str) -> Result<String, &'static str> {
+    let username = username.trim();
+    if username.is_empty() {
+        return Err("invalid username, can't be empty");
+    }
+    if username.chars().count() > MAX_USERNAME_LEN {
+        return Err("invalid username, max 32 chars");
+    }
+    if username.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("invalid username, no whitespace or control chars");
+    }
+    Ok(username.into())
+}
+
+// What a user had when they disconnected, for `resume <token>` as the first line
+// of a new connection. The username isn't held on to, so someone else may take it.
+#[derive(Debug)]
+struct Session {
+    token: String,
+    username: String,
+    rooms: Vec<Room>,
+    allowed_commands: Option<HashSet<String>>,
+    expires: Instant,
+}
+
+// 128 bits from the OS's random number generator
+fn new_session_token() -> String {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).expect("no random numbers from the OS");
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// Takes as long for a wrong first char as for a wrong last one,
+// so the admin token can't be guessed a char at a time
+fn same_token(expected: &str, given: &str) -> bool {
+    expected.len() == given.len() && expected.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Bans are by name, and by connection so that a new `nick` doesn't get around them
+fn is_banned(bans: &HashMap<Room, HashSet<String>>, banned_connections: &HashMap<Room, HashSet<usize>>, room_name: &str, sender: &Sender) -> bool {
+    bans.get(room_name).is_some_and(|banned| banned.contains(&sender.username))
+        || banned_connections.get(room_name).is_some_and(|banned| banned.contains(&sender.id))
+}
+
+// Look up a room for a command only its members may use.
+// Tells the user why if the room doesn't exist or they are not a member.
+fn member_room<'a>(
+    rooms: &'a mut HashMap<Room, ChatRoom>,
+    room_name: &str,
+    sender: &Sender,
+) -> Option<&'a mut ChatRoom> {
+    // The room may have been removed just before the command got here,
+    // when its last member left
+    let Some(room) = rooms.get_mut(room_name) else {
+        sender.reject(Rejection::NoSuchRoom(room_name));
+        return None;
+    };
+    if !room.members.contains_key(&sender.id) {
+        sender.reject(Rejection::NotMember(room_name));
+        return None;
+    }
+    Some(room)
+}
+
+// Look up a room for a command only its operator may use.
+// Tells the user why if the room doesn't exist or they are not the operator.
+fn operated_room<'a>(
+    rooms: &'a mut HashMap<Room, ChatRoom>,
+    room_name: &str,
+    sender: &Sender,
+) -> Option<&'a mut ChatRoom> {
+    let Some(room) = rooms.get_mut(room_name) else {
+        sender.reject(Rejection::NoSuchRoom(room_name));
+        return None;
+    };
+    if room.operator != sender.id {
+        sender.reject(Rejection::NotOperator(room_name));
+        return None;
+    }
+    Some(room)
+}
+
+// What the rooms task is sent: commands from users, and the reader's
+// bookkeeping about the connection itself
+enum Request {
+    Command(Command, Arc<Sender>),
+    // Sent by the reader once the user has a name
+    Connect(Arc<Sender>),
+    // Sent by the reader when the connection goes away.
+    // The rooms task fills in the rooms of the session and saves it.
+    Disconnect(Arc<Sender>, Option<Session>),
+    // Takes the session with this token, if it hasn't expired.
+    // A session can only be resumed once.
+    Resume { token: String, reply: oneshot::Sender<Option<Session>> },
+    // Sent by `relay_room` when a link fails or the other server goes away
+    Unlinked { room: Room, id: usize },
+}
+
+// `requests` is for the links to report back with, weak so the rooms task
+// still ends once every connection is gone. The rooms are saved as soon as
+// `shutdown` says so, before the connections leave them.
+async fn rooms(
+    mut receiver: RoomReceiver,
+    requests: mpsc::WeakSender<Request>,
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut rooms: HashMap<Room, ChatRoom> = HashMap::new(); // contains room names as key, and a bunch of senders
+    let mut bans: HashMap<Room, HashSet<String>> = HashMap::new(); // room name -> banned usernames
+    let mut banned_connections: HashMap<Room, HashSet<usize>> = HashMap::new(); // room name -> connection ids of banned users
+    let mut rosters: HashMap<(usize, Room), HashSet<String>> = HashMap::new(); // (sender id, room name) -> last roster sent
+    let mut users: HashMap<String, Arc<Sender>> = HashMap::new(); // username -> sender
+    let mut sessions: HashMap<String, Session> = HashMap::new(); // token -> session of a user who disconnected
+    let mut last_rooms: HashMap<String, (Vec<Room>, Instant)> = HashMap::new(); // username -> rooms at its last disconnect, until when
+    let mut history_log = match config.history_file.as_deref() {
+        Some(path) => match HistoryLog::open(path, config.history_len, HISTORY_FILE_MAX_SIZE).await {
+            Ok(log) => Some(log),
+            Err(e) => {
+                warn!("failed to open history log {path}: {e}");
+                None
+            }
+        },
+        None => None,
+    };
+    // What was saved for rooms that haven't been created again yet, by lowercased name
+    let mut saved_rooms: HashMap<Room, SavedRoom> = HashMap::new();
+    if let Some(path) = config.rooms_file.as_deref() {
+        match SavedRooms::load(path).await {
+            Ok(saved) => {
+                info!("restored {} rooms from {path}", saved.rooms.len());
+                saved_rooms = saved.rooms.into_iter().map(|room| (canonical_room(&room.name), room)).collect();
+                bans = saved.bans.into_iter().map(|(room_name, banned)| (room_name, banned.into_iter().collect())).collect();
+            }
+            Err(e) => warn!("failed to restore rooms from {path}: {e}"),
+        }
+    }
+    let mut saved = false;
+
+    loop {
+        let request = tokio::select! {
+            biased;
+            // The only change there is, from running to shutting down
+            Ok(()) = shutdown.changed(), if !saved => {
+                saved = true;
+                if let Some(path) = config.rooms_file.as_deref() {
+                    save_rooms(path, &rooms, &saved_rooms, &bans).await;
+                }
+                continue;
+            }
+            request = receiver.recv() => match request {
+                Some(request) => request,
+                None => break,
+            },
+        };
+        let (mut command, sender) = match request {
+            Request::Command(command, sender) => (command, sender),
+            Request::Connect(sender) => {
+                users.insert(sender.username.clone(), sender);
+                continue;
+            }
+            // Leave every room, otherwise the dead sender lingers in them forever
+            Request::Disconnect(sender, session) => {
+                users.retain(|_, s| s != &sender);
+                // Ids aren't reused, so mutes of this connection are no use to anyone
+                for user in users.values() {
+                    user.muted.lock().unwrap().remove(&sender.id);
+                }
+                let joined: Vec<Room> = rooms
+                    .iter()
+                    .filter(|(_, room)| room.members.contains_key(&sender.id))
+                    .map(|(room_name, _)| room_name.clone())
+                    .collect();
+                for room_name in &joined {
+                    part_room(&mut rooms, room_name, &sender);
+                }
+                let now = Instant::now();
+                last_rooms.retain(|_, (_, expires)| *expires > now);
+                if !joined.is_empty() {
+                    last_rooms.insert(sender.username.clone(), (joined.clone(), now + config.session_ttl));
+                }
+                if let Some(mut session) = session {
+                    session.rooms = joined;
+                    sessions.retain(|_, session| session.expires > now);
+                    sessions.insert(session.token.clone(), session);
+                }
+                for room in rooms.values_mut() {
+                    if room.watchers.contains(&sender) {
+                        room.watchers.retain(|s| s != &sender);
+                        room.unsubscribe(sender.id);
+                    }
+                }
+                rosters.retain(|(id, _), _| *id != sender.id);
+                // Ids aren't reused, so a new connection is only held to the name bans
+                banned_connections.retain(|_, banned| {
+                    banned.remove(&sender.id);
+                    !banned.is_empty()
+                });
+                continue;
+            }
+            Request::Resume { token, reply } => {
+                let session = sessions.remove(&token).filter(|session| session.expires > Instant::now());
+                let _ = reply.send(session);
+                continue;
+            }
+            // Unless the room was unlinked (and maybe linked again) in the meantime
+            Request::Unlinked { room: room_name, id } => {
+                if let Some(room) = rooms.get_mut(&room_name).filter(|room| room.link == Some(id)) {
+                    room.link = None;
+                    room.watchers.retain(|s| s.id != id);
+                    room.unsubscribe(id);
+                }
+                continue;
+            }
+        };
+        let display_name = command.canonicalize_rooms();
+        // Raw connections are told once the command is done. Refusals `continue`
+        // past this, they have already been answered with `ERR <reason>`.
+        let done = sender.raw.then(|| (command.name(), sender.clone()));
+        match command {
+            Command::Join(room_name) => {
+                if is_banned(&bans, &banned_connections, &room_name, &sender) {
+                    sender.reject(Rejection::Banned(&room_name));
+                    continue;
+                }
+                if !join_room(&mut rooms, room_name, display_name, sender, &config, &state, &mut history_log, &mut saved_rooms) {
+                    continue;
+                }
+            }
+            Command::Part(room_name) => {
+                if !rooms.get(&room_name).is_some_and(|room| room.members.contains_key(&sender.id)) {
+                    match sender.raw {
+                        true => sender.reject(Rejection::NotMember(&room_name)),
+                        false => sender.notice(&format!("* part dropped: you are not in {room_name}")),
+                    }
+                    continue;
+                }
+                part_room(&mut rooms, &room_name, &sender);
+                rosters.remove(&(sender.id, room_name));
+            }
+            Command::Switch { from, to } => {
+                // Check everything up front, so the user is either moved or left where they are
+                if !rooms.get(&from).is_some_and(|room| room.members.contains_key(&sender.id)) {
+                    sender.reject(Rejection::NotMember(&from));
+                    continue;
+                }
+                if is_banned(&bans, &banned_connections, &to, &sender) {
+                    sender.reject(Rejection::Banned(&to));
+                    continue;
+                }
+                if from == to || rooms.get(&to).is_some_and(|room| room.members.contains_key(&sender.id)) {
+                    sender.reject(Rejection::AlreadyJoined(&to));
+                    continue;
+                }
+                if rooms.get(&to).is_some_and(|room| room.members.len() >= MAX_ROOM_MEMBERS) {
+                    sender.reject(Rejection::RoomFull(&to));
+                    continue;
+                }
+                part_room(&mut rooms, &from, &sender);
+                rosters.remove(&(sender.id, from));
+                if !join_room(&mut rooms, to, display_name, sender, &config, &state, &mut history_log, &mut saved_rooms) {
+                    continue;
+                }
+            }
+            Command::Msg { room: room_name, msg } => {
+                let Some(room) = member_room(&mut rooms, &room_name, &sender) else { continue };
+                let Some(msg) = config.filter_message(msg) else {
+                    sender.reject(Rejection::Blocked);
+                    continue;
+                };
+                let bytes = chat_line(&config, &sender.username, &msg);
+                if let Some(log) = &mut history_log {
+                    log.append(&room_name, &bytes);
+                }
+                room.post(sender.id, bytes);
+            }
+            Command::MultiMsg { room: room_name, msgs } => {
+                let Some(room) = member_room(&mut rooms, &room_name, &sender) else { continue };
+                // Each message is broadcast on its own line
+                for msg in msgs {
+                    let Some(msg) = config.filter_message(msg) else {
+                        sender.reject(Rejection::Blocked);
+                        continue;
+                    };
+                    let bytes = chat_line(&config, &sender.username, &msg);
+                    if let Some(log) = &mut history_log {
+                        log.append(&room_name, &bytes);
+                    }
+                    room.post(sender.id, bytes);
+                }
+            }
+            Command::Broadcast(msg) => {
+                let Some(msg) = config.filter_message(msg) else {
+                    sender.reject(Rejection::Blocked);
+                    continue;
+                };
+                let joined: Vec<_> = rooms.iter_mut().filter(|(_, room)| room.members.contains_key(&sender.id)).collect();
+                if joined.is_empty() {
+                    sender.reject(Rejection::NoRooms);
+                    continue;
+                }
+                let bytes = chat_line(&config, &format!("{} (broadcast)", sender.username), &msg);
+                for (room_name, room) in joined {
+                    if let Some(log) = &mut history_log {
+                        log.append(room_name, &bytes);
+                    }
+                    room.post(sender.id, bytes.clone());
+                }
+            }
+            Command::Me { room: room_name, action } => {
+                let Some(room) = member_room(&mut rooms, &room_name, &sender) else { continue };
+                let Some(action) = config.filter_message(action) else {
+                    sender.reject(Rejection::Blocked);
+                    continue;
+                };
+                let bytes: Arc<[u8]> = format!("* {} {action}\n", sender.username).into_bytes().into();
+                if let Some(log) = &mut history_log {
+                    log.append(&room_name, &bytes);
+                }
+                room.post(sender.id, bytes);
+            }
+            Command::Topic { room: room_name, topic: None } => {
+                let Some(room) = member_room(&mut rooms, &room_name, &sender) else { continue };
+                match &room.topic {
+                    Some(topic) => sender.reply(&format!("* topic for {room_name}: {topic}")),
+                    None => sender.reply(&format!("* no topic set for {room_name}")),
+                }
+            }
+            Command::Topic { room: room_name, topic: Some(topic) } => {
+                let Some(room) = member_room(&mut rooms, &room_name, &sender) else { continue };
+                let Some(topic) = config.filter_message(topic) else {
+                    sender.reject(Rejection::Blocked);
+                    continue;
+                };
+                room_notice(room, format!("* topic for {room_name} set to: {topic}\n"));
+                room.topic = Some(topic);
+            }
+            Command::Ban { room: room_name, username } => {
+                let Some(room) = operated_room(&mut rooms, &room_name, &sender) else { continue };
+
+                // Anyone by that name currently in the room is removed right away
+                let banned: Vec<_> = room.members.values().filter(|s| s.username == username).cloned().collect();
+                for member in &banned {
+                    room.members.remove(&member.id);
+                    room.unsubscribe(member.id);
+                    rosters.remove(&(member.id, room_name.clone()));
+                }
+                if !banned.is_empty() {
+                    room_notice(room, format!("* {username} was banned from {room_name}\n"));
+                }
+                if room.members.is_empty() {
+                    rooms.remove(&room_name);
+                    debug!("room {room_name} is empty, removing");
+                }
+                let connections = banned_connections.entry(room_name.clone()).or_default();
+                connections.extend(banned.iter().chain(users.get(&username)).map(|s| s.id));
+                for member in banned {
+                    member.reject(Rejection::Banned(&room_name));
+                }
+
+                info!("conn {} banned {username} from room {room_name}", sender.id);
+                bans.entry(room_name).or_default().insert(username);
+            }
+            // Like a ban, but they can join again straight away
+            Command::Kick { room: room_name, username } => {
+                let Some(room) = operated_room(&mut rooms, &room_name, &sender) else { continue };
+                let Some(kicked) = room.members.values().find(|s| s.username == username).cloned() else {
+                    sender.reject(Rejection::UserNotMember { username: &username, room: &room_name });
+                    continue;
+                };
+                room.members.remove(&kicked.id);
+                room.unsubscribe(kicked.id);
+                room_notice(room, format!("* {username} was kicked from {room_name}\n"));
+                if room.members.is_empty() {
+                    rooms.remove(&room_name);
+                    debug!("room {room_name} is empty, removing");
+                }
+                rosters.remove(&(kicked.id, room_name.clone()));
+                kicked.reply(&format!("* you were kicked from {room_name}"));
+                info!("conn {} kicked {username} from room {room_name}", sender.id);
+            }
+            Command::SetGreeting { room: room_name, greeting } => {
+                let Some(room) = operated_room(&mut rooms, &room_name, &sender) else { continue };
+                room.greeting = Some(greeting);
+            }
+            Command::SetMotd { room: room_name, motd } => {
+                let Some(room) = operated_room(&mut rooms, &room_name, &sender) else { continue };
+                room.motd = Some(motd);
+            }
+            Command::ClearMotd(room_name) => {
+                let Some(room) = operated_room(&mut rooms, &room_name, &sender) else { continue };
+                room.motd = None;
+            }
+            Command::Watch(room_name) => {
+                let Some(room) = rooms.get_mut(&room_name) else {
+                    sender.reject(Rejection::NoSuchRoom(&room_name));
+                    continue;
+                };
+                if !room.recipients().any(|s| s == &sender) {
+                    room.subscribe(&room_name, &sender);
+                    room.watchers.push(sender);
+                }
+            }
+            Command::Unwatch(room_name) => {
+                let Some(room) = rooms.get_mut(&room_name) else {
+                    sender.reject(Rejection::NoSuchRoom(&room_name));
+                    continue;
+                };
+                if room.watchers.contains(&sender) {
+                    room.watchers.retain(|s| s != &sender);
+                    room.unsubscribe(sender.id);
+                }
+            }
+            Command::Admin(token) => {
+                if !config.admin_token.as_deref().is_some_and(|admin_token| same_token(admin_token, &token)) {
+                    sender.reject(Rejection::InvalidToken);
+                    continue;
+                }
+                sender.admin.store(true, Ordering::Relaxed);
+                sender.confirm("* you are an admin");
+            }
+            Command::Config => {
+                if !sender.admin.load(Ordering::Relaxed) {
+                    sender.reject(Rejection::NotAdmin);
+                    continue;
+                }
+                for setting in config.describe() {
+                    sender.reply(&format!("* config {setting}"));
+                }
+            }
+            Command::Link { room: room_name, addr } => {
+                if !sender.admin.load(Ordering::Relaxed) {
+                    sender.reject(Rejection::NotAdmin);
+                    continue;
+                }
+                let Some(room) = rooms.get_mut(&room_name) else {
+                    sender.reject(Rejection::NoSuchRoom(&room_name));
+                    continue;
+                };
+                if room.link.is_some() {
+                    sender.reject(Rejection::AlreadyLinked(&room_name));
+                    continue;
+                }
+
+                // The relay watches the room like any other connection would,
+                // so dropping the watcher (unlink, or the room going away) ends the relay
+                let (inner, receiver) = mpsc::channel(100);
+                let relay = Sender {
+                    inner,
+                    id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+                    username: format!("relay:{addr}"),
+                    stats: Arc::new(ConnectionStats::new()),
+                    verbose: AtomicBool::new(false),
+                    echo: Arc::new(AtomicBool::new(false)),
+                    lagging: AtomicBool::new(false),
+                    muted: Default::default(),
+                    raw: false,
+                    admin: Default::default(),
+                    invisible: Default::default(),
+                };
+                let relay = Arc::new(relay);
+                room.link = Some(relay.id);
+                room.subscribe(&room_name, &relay);
+                sender.confirm(&format!("* linking {room_name} to {addr}"));
+                let link = Link {
+                    addr,
+                    room: room_name,
+                    id: relay.id,
+                    token: config.link_token.clone(),
+                    linker: sender.clone(),
+                    requests: requests.clone(),
+                };
+                room.watchers.push(relay);
+                tokio::spawn(relay_room(link, receiver));
+            }
+            Command::Unlink(room_name) => {
+                if !sender.admin.load(Ordering::Relaxed) {
+                    sender.reject(Rejection::NotAdmin);
+                    continue;
+                }
+                let Some(room) = rooms.get_mut(&room_name) else {
+                    sender.reject(Rejection::NoSuchRoom(&room_name));
+                    continue;
+                };
+                let Some(id) = room.link.take() else {
+                    sender.reject(Rejection::NotLinked(&room_name));
+                    continue;
+                };
+                room.watchers.retain(|s| s.id != id);
+                room.unsubscribe(id);
+                sender.confirm(&format!("* unlinked {room_name}"));
+            }
+            Command::Since { room: room_name, seq } => {
+                let Some(room) = member_room(&mut rooms, &room_name, &sender) else { continue };
+                for entry in room.history.iter().filter(|entry| entry.seq > seq) {
+                    sender.deliver(replay(&config, &room.display_name, entry));
+                }
+            }
+            Command::React { room: room_name, seq, emoji } => {
+                let Some(room) = member_room(&mut rooms, &room_name, &sender) else { continue };
+                if !room.history.iter().any(|entry| entry.seq == seq) {
+                    sender.reject(Rejection::NoSuchMessage(seq));
+                    continue;
+                }
+                let reactions = room.reactions.entry(seq).or_default();
+                if !reactions.contains_key(&emoji) && reactions.len() >= MAX_REACTIONS {
+                    sender.reject(Rejection::TooManyReactions(seq));
+                    continue;
+                }
+                // Reacting the same way twice counts once
+                if reactions.entry(emoji.clone()).or_default().insert(sender.username.clone()) {
+                    let notice = format!("* {} reacted {emoji} to #{seq}\n", sender.username);
+                    room.broadcast(None, notice.into_bytes().into());
+                }
+            }
+            // Between two notices, so a client knows where the history ends
+            Command::Export(room_name) => {
+                let Some(room) = member_room(&mut rooms, &room_name, &sender) else { continue };
+                sender.reply(&format!("* export of {}: {} messages", room.display_name, room.history.len()));
+                for entry in &room.history {
+                    sender.deliver(replay(&config, &room.display_name, entry));
+                }
+                sender.reply(&format!("* end of export of {}", room.display_name));
+            }
+            Command::IsOnline(username) => {
+                let online = users.get(&username).is_some_and(|user| !user.invisible.load(Ordering::Relaxed));
+                sender.reply(&format!("* {username} is {}", if online { "online" } else { "offline" }));
+            }
+            Command::Recall(n) => {
+                let mut recalled: Vec<(&Room, &HistoryEntry)> = rooms
+                    .values()
+                    .filter(|room| room.members.contains_key(&sender.id))
+                    .flat_map(|room| room.history.iter().map(move |entry| (&room.display_name, entry)))
+                    .collect();
+                recalled.sort_by_key(|(_, entry)| entry.posted);
+                let skip = recalled.len().saturating_sub(n.min(MAX_RECALL));
+                for (room_name, entry) in &recalled[skip..] {
+                    sender.deliver(replay(&config, room_name, entry));
+                }
+            }
+            Command::Typing(room_name) => {
+                let Some(room) = rooms.get(&room_name).filter(|room| room.members.contains_key(&sender.id)) else {
+                    match sender.raw {
+                        true => sender.reject(Rejection::NotMember(&room_name)),
+                        false => sender.notice(&format!("* typing notice dropped: you are not in {room_name}")),
+                    }
+                    continue;
+                };
+                // Typing notices are transient, they only go to the members here and now
+                let notice = format!("* {} is typing in {room_name}\n", sender.username);
+                let typing = Outgoing { room: Some(room.display_name.clone()), seq: None, bytes: notice.into_bytes().into() };
+                for recipient in room.members.values().filter(|s| *s != &sender && !s.is_muted(sender.id)) {
+                    recipient.deliver(typing.clone());
+                }
+            }
+            Command::Roster(room_name) => {
+                let Some(room) = rooms.get(&room_name).filter(|room| room.members.contains_key(&sender.id)) else {
+                    sender.reject(Rejection::NotMember(&room_name));
+                    continue;
+                };
+
+                let current: HashSet<String> = room.members.values().map(|s| s.username.clone()).collect();
+                // The first roster is the full list, after that only the changes
+                let reply = match rosters.get(&(sender.id, room_name.clone())) {
+                    None => {
+                        let mut names: Vec<&str> = room.members.values().map(|s| s.username.as_str()).collect();
+                        names.sort();
+                        format!("* roster {room_name}: {}", names.join(", "))
+                    }
+                    Some(previous) => {
+                        let mut joined: Vec<_> = current.difference(previous).map(|name| format!("+{name}")).collect();
+                        let mut left: Vec<_> = previous.difference(&current).map(|name| format!("-{name}")).collect();
+                        joined.sort();
+                        left.sort();
+                        joined.append(&mut left);
+                        if joined.is_empty() {
+                            format!("* roster {room_name}: no changes")
+                        } else {
+                            format!("* roster {room_name}: {}", joined.join(" "))
+                        }
+                    }
+                };
+                rosters.insert((sender.id, room_name), current);
+                sender.reply(&reply);
+            }
+            Command::RoomCaps(room_name) => {
+                let Some(room) = member_room(&mut rooms, &room_name, &sender) else { continue };
+                let on_off = |set: bool| if set { "on" } else { "off" };
+                let compress = room.history_compress.map_or("off".into(), |len| len.to_string());
+                sender.reply(&format!(
+                    "* roomcaps {room_name}: members {}/{MAX_ROOM_MEMBERS}, history {}, compress {compress}, topic {}, motd {}, greeting {}, link {}",
+                    room.members.len(),
+                    room.history_len,
+                    on_off(room.topic.is_some()),
+                    on_off(room.motd.is_some()),
+                    on_off(room.greeting.is_some()),
+                    on_off(room.link.is_some()),
+                ));
+            }
+            // Every room with its member count, by name
+            Command::List => {
+                let mut names: Vec<&Room> = rooms.keys().collect();
+                names.sort();
+                let listing: Vec<String> = names
+                    .iter()
+                    .map(|name| format!("{} ({})", rooms[*name].display_name, rooms[*name].members.len()))
+                    .collect();
+                if listing.is_empty() {
+                    sender.reply("* rooms: none");
+                } else {
+                    sender.reply(&format!("* rooms: {}", listing.join(", ")));
+                }
+            }
+            // Each room is joined as if by `join`, refusals included
+            Command::LoadRooms => {
+                let saved = last_rooms.remove(&sender.username).filter(|(_, expires)| *expires > Instant::now());
+                let Some((mut saved, _)) = saved else {
+                    sender.reply("* no rooms to load");
+                    continue;
+                };
+                saved.sort();
+                let mut loaded = Vec::new();
+                for room_name in saved {
+                    if is_banned(&bans, &banned_connections, &room_name, &sender) {
+                        sender.reject(Rejection::Banned(&room_name));
+                        continue;
+                    }
+                    if join_room(&mut rooms, room_name.clone(), None, sender.clone(), &config, &state, &mut history_log, &mut saved_rooms) {
+                        loaded.push(room_name);
+                    }
+                }
+                sender.reply(&format!("* loaded rooms: {}", if loaded.is_empty() { "none".into() } else { loaded.join(", ") }));
+            }
+            // Members sorted by name
+            Command::Who(room_name) => {
+                let Some(room) = rooms.get(&room_name).filter(|room| room.members.contains_key(&sender.id)) else {
+                    sender.reject(Rejection::NotMember(&room_name));
+                    continue;
+                };
+                let mut names: Vec<&str> = room.members.values().map(|s| s.username.as_str()).collect();
+                names.sort();
+                sender.reply(&format!("* users in {room_name}: {}", names.join(", ")));
+            }
+            // Scheduled messages, verbosity, echo, visibility, frame tests and line limits are managed by the connection's reader
+            Command::Schedule { .. }
+            | Command::CancelSchedule(_)
+            | Command::Schedules
+            | Command::Help
+            | Command::Quit
+            | Command::Verbose(_)
+            | Command::Echo(_)
+            | Command::Invisible(_)
+            | Command::FrameTest
+            | Command::BufLimit(_) => {}
+            // The reader has already built the renamed sender,
+            // swap it in wherever the old one is
+            Command::Nick(nick) => {
+                users.retain(|_, s| s != &sender);
+                users.insert(nick.clone(), sender.clone());
+
+                let mut renamed = false;
+                for room in rooms.values_mut() {
+                    if let Some(watcher) = room.watchers.iter_mut().find(|s| **s == sender) {
+                        *watcher = sender.clone();
+                    }
+                    let Some(member) = room.members.get_mut(&sender.id) else { continue };
+                    let old = std::mem::replace(member, sender.clone());
+
+                    room_notice(room, format!("* {} is now known as {nick}\n", old.username));
+                    renamed = true;
+                }
+                if !renamed {
+                    sender.confirm(&format!("* you are now known as {nick}"));
+                }
+            }
+            Command::Pm { to, msg } => {
+                let Some(recipient) = users.get(&to) else {
+                    sender.reject(Rejection::NoSuchUser(&to));
+                    continue;
+                };
+                let Some(msg) = config.filter_message(msg) else {
+                    sender.reject(Rejection::Blocked);
+                    continue;
+                };
+                // Muted users aren't told, it would only invite them to find another way
+                if recipient.is_muted(sender.id) {
+                    recipient.notice(&format!("* pm from {} dropped: sender muted", sender.username));
+                } else {
+                    let bytes = chat_line(&config, &format!("[pm] {}", sender.username), &msg);
+                    recipient.deliver(bytes);
+                }
+            }
+            // Mutes are by connection, so they last until the muted user disconnects
+            Command::Mute(username) => {
+                let Some(muted) = users.get(&username).filter(|muted| **muted != sender) else {
+                    sender.reject(Rejection::NoSuchUser(&username));
+                    continue;
+                };
+                let mut mutes = sender.muted.lock().unwrap();
+                if mutes.len() >= config.max_mutes && !mutes.contains(&muted.id) {
+                    sender.reject(Rejection::MutesFull);
+                    continue;
+                }
+                mutes.insert(muted.id);
+                sender.confirm(&format!("* muted {username}"));
+            }
+            Command::Unmute(username) => {
+                let Some(muted) = users.get(&username) else {
+                    sender.reject(Rejection::NoSuchUser(&username));
+                    continue;
+                };
+                sender.muted.lock().unwrap().remove(&muted.id);
+                sender.confirm(&format!("* unmuted {username}"));
+            }
+            Command::MyStats => {
+                let stats = &sender.stats;
+                let joined = rooms.values().filter(|room| room.members.contains_key(&sender.id)).count();
+                let reply = format!(
+                    "* connected {}s, sent {} messages, received {} messages, in {joined} rooms, {} bytes in, {} bytes out",
+                    stats.connected_at.elapsed().as_secs(),
+                    stats.messages_sent.load(Ordering::Relaxed),
+                    stats.messages_received.load(Ordering::Relaxed),
+                    stats.bytes_read.load(Ordering::Relaxed),
+                    stats.bytes_written.load(Ordering::Relaxed),
+                );
+                sender.reply(&reply);
+            }
+            Command::Stats => {
+                let reply = format!(
+                    "* server: {} connections, {} active, {} messages routed, {} rooms created, {} open",
+                    state.total_connections.load(Ordering::Relaxed),
+                    state.active_connections.load(Ordering::Relaxed),
+                    state.messages_routed.load(Ordering::Relaxed),
+                    state.rooms_created.load(Ordering::Relaxed),
+                    rooms.len(),
+                );
+                sender.reply(&reply);
+            }
+        }
+        if let Some((name, sender)) = done {
+            sender.reply(&format!("OK {name}"));
+        }
+    }
+}
+
+// Every room there is, and the ones restored on startup that nobody joined since
+async fn save_rooms(
+    path: &str,
+    rooms: &HashMap<Room, ChatRoom>,
+    saved_rooms: &HashMap<Room, SavedRoom>,
+    bans: &HashMap<Room, HashSet<String>>,
+) {
+    let current = rooms.values().map(|room| SavedRoom {
+        name: room.display_name.clone(),
+        topic: room.topic.clone(),
+        motd: room.motd.clone(),
+        greeting: room.greeting.clone(),
+    });
+    let not_joined = saved_rooms.values().map(|room| SavedRoom {
+        name: room.name.clone(),
+        topic: room.topic.clone(),
+        motd: room.motd.clone(),
+        greeting: room.greeting.clone(),
+    });
+    let saved = SavedRooms {
+        rooms: current.chain(not_joined).collect(),
+        bans: bans.iter().map(|(room_name, banned)| (room_name.clone(), banned.iter().cloned().collect())).collect(),
+    };
+    match saved.save(path).await {
+        Ok(()) => info!("saved {} rooms to {path}", saved.rooms.len()),
+        Err(e) => warn!("failed to save rooms to {path}: {e}"),
+    }
+}
+
+// -----------------------------------------------------------------------------
+//   - Links -
+//   Relay a local room to the room with the same name on another server.
+//   This is one way only: the relay connects like any other client (in raw
+//   mode, as `relay-<random>` so several links can go to the same server),
+//   joins the room and posts every message it receives as its own.
+//   Whoever linked the room is told if that fails or the other server goes
+//   away, and the room is unlinked so it can be linked again.
+// -----------------------------------------------------------------------------
+// How long the other server gets to let the relay into the room
+const LINK_TIMEOUT: Duration = Duration::from_secs(10);
+// A quiet room's relay pings the other server this often,
+// so it isn't disconnected for being idle
+const LINK_KEEPALIVE: Duration = Duration::from_secs(30);
+
+struct Link {
+    addr: String,
+    room: Room,
+    // Of the relay watching the room, see `Request::Unlinked`
+    id: usize,
+    // Sent with `auth` if the other server asks for it
+    token: Option<String>,
+    linker: Arc<Sender>,
+    requests: mpsc::WeakSender<Request>,
+}
+
+impl Link {
+    // Tell the linker and clear the room's link
+    async fn failed(&self, reason: &str) {
+        warn!("link of room {} to {}: {reason}", self.room, self.addr);
+        self.linker.reply(&format!("* link of {} to {} failed: {reason}", self.room, self.addr));
+        if let Some(requests) = self.requests.upgrade() {
+            let _ = requests.send(Request::Unlinked { room: self.room.clone(), id: self.id }).await;
+        }
+    }
+
+    // Log in and join the room, the way a raw client would
+    async fn connect(&self) -> std::io::Result<(Lines<BufReader<OwnedReadHalf>>, OwnedWriteHalf)> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        let username = format!("relay-{}", &new_session_token()[..8]);
+        writer.write_all(format!("mode raw\n{username}\n").as_bytes()).await?;
+        loop {
+            let Some(line) = lines.next_line().await? else {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            };
+            match line.as_str() {
+                "OK username" => match &self.token {
+                    Some(token) => writer.write_all(format!("auth {token}\n").as_bytes()).await?,
+                    None => return Err(std::io::Error::other("a token is required, see CHATTERY_LINK_TOKEN")),
+                },
+                _ if line.starts_with("OK login ") => break,
+                _ if line.starts_with("ERR ") => return Err(std::io::Error::other(format!("login refused: {line}"))),
+                _ => {}
+            }
+        }
+
+        writer.write_all(format!("join {}\n", self.room).as_bytes()).await?;
+        loop {
+            let Some(line) = lines.next_line().await? else {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            };
+            match line.as_str() {
+                "OK join" => return Ok((lines, writer)),
+                _ if line.starts_with("ERR ") => return Err(std::io::Error::other(format!("join refused: {line}"))),
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn relay_room(link: Link, mut receiver: Receiver<Outgoing>) {
+    let (mut lines, mut writer) = match tokio::time::timeout(LINK_TIMEOUT, link.connect()).await {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(e)) => return link.failed(&e.to_string()).await,
+        Err(_) => return link.failed("timed out").await,
+    };
+    info!("linked room {} to {}", link.room, link.addr);
+    link.linker.confirm(&format!("* linked {} to {}", link.room, link.addr));
+
+    let mut keepalive = tokio::time::interval(LINK_KEEPALIVE);
+    keepalive.reset();
+    loop {
+        tokio::select! {
+            message = receiver.recv() => {
+                // Unlinked, or the room went away
+                let Some(message) = message else { break };
+                let mut payload = Vec::with_capacity(link.room.len() + message.bytes.len() + 5);
+                payload.extend(b"msg ");
+                payload.extend(link.room.as_bytes());
+                payload.push(b' ');
+                payload.extend(message.bytes.iter());
+                if writer.write_all(&payload).await.is_err() {
+                    return link.failed("connection lost").await;
+                }
+            }
+            // Whatever the other server sends has to be read, or it will
+            // eventually stop sending to everyone in that room
+            line = lines.next_line() => match line {
+                Ok(Some(line)) if line == "ping" => {
+                    if writer.write_all(b"pong\n").await.is_err() {
+                        return link.failed("connection lost").await;
+                    }
+                }
+                Ok(Some(line)) if line.starts_with("ERR ") => warn!("link of room {} to {}: {line}", link.room, link.addr),
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => return link.failed("connection lost").await,
+            },
+            _ = keepalive.tick() => {
+                if writer.write_all(b"ping\n").await.is_err() {
+                    return link.failed("connection lost").await;
+                }
+            }
+        }
+    }
+
+    info!("unlinked room {} from {}", link.room, link.addr);
+}
+
+// Backpressure: commands from users never wait on the rooms task.
+// If its queue is full (or it is gone) the command is dropped and the user
+// is told. Commands that keep the rooms task's bookkeeping right
+// (connect, nick and disconnect) are sent with `send` and wait instead.
+async fn send_to_rooms(room_sender: &RoomSender, command: Command, sender: &Arc<Sender>) {
+    if room_sender.try_send(Request::Command(command, sender.clone())).is_err() {
+        sender.status("ERR busy", "* server busy, message dropped");
+    }
+}
+
+// A message waiting to be sent, see `schedule`
+struct ScheduledMessage {
+    room: Room,
+    // The start of the message, for `schedules`
+    preview: String,
+    send_at: Instant,
+    handle: AbortHandle,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_reader<R: AsyncRead + Unpin>(
+    mut reader: R,
+    sender: mpsc::Sender<Outgoing>,
+    id: usize,
+    room_sender: RoomSender,
+    config: Arc<Config>,
+    stats: Arc<ConnectionStats>,
+    width: Arc<AtomicUsize>,
+    shared: Arc<ServerState>,
+    mut shutdown: Shutdown,
+) {
+    let mut state = State::Anon;
+    // None means every command is allowed
+    let mut allowed_commands: Option<HashSet<String>> = None;
+    // Handed out once the user has a name, to `resume` with after a disconnect
+    let mut session_token: Option<String> = None;
+    // Pending scheduled messages by id
+    let mut scheduled: HashMap<usize, ScheduledMessage> = HashMap::new();
+    let mut next_schedule_id = 1;
+    // Who scheduled messages go out as when they fire, kept up to date by `nick`
+    let mut scheduled_as: Option<watch::Sender<Arc<Sender>>> = None;
+    let mut last_typing: Option<Instant> = None;
+    let mut command_rate = TokenBucket::per(config.command_rate, COMMAND_RATE_PERIOD);
+    let mut frame = new_framing(&config);
+    let mut last_read = Instant::now();
+    let mut awaiting_pong = false;
+    // Set with `mode raw`, see `status`
+    let mut raw = false;
+    let mut auth_failures = 0;
+    // The banner and username prompt wait for the first line, see `status`
+    let mut prompted = false;
+    let wait = config.ping_interval.map_or(config.idle_timeout, |interval| interval.min(config.idle_timeout));
+    'reader: loop {
+        // Step 1: read into the `frame`
+        let wait = if prompted { wait } else { PROMPT_DELAY };
+        let read = tokio::select! {
+            read = tokio::time::timeout(wait, reader.read(frame.unfilled())) => read,
+            // The writer says goodbye, the reader only has to stop
+            Ok(_) = shutdown.signal.wait_for(|shutting_down| *shutting_down) => break 'reader,
+        };
+        let Ok(read) = read else {
+            if !prompted {
+                prompted = true;
+                send_prompt(&sender).await;
+                continue;
+            }
+            // Nothing read for too long, leaving cleans up like any other disconnect
+            if last_read.elapsed() >= config.idle_timeout {
+                let _ = sender.send(b"* disconnected due to inactivity\n"[..].into()).await;
+                break 'reader;
+            }
+            // Quiet for a ping interval, the link may be dead
+            if awaiting_pong {
+                let _ = sender.send(b"* disconnected, no pong\n"[..].into()).await;
+                break 'reader;
+            }
+            let _ = sender.send(b"ping\n"[..].into()).await;
+            awaiting_pong = true;
+            continue;
+        };
+        last_read = Instant::now();
+        match read {
+            // Read zero bytes means the socket hung up on the other end.
+            // This could be that the user just closed the connection, or
+            // killed the program, or just turned off their computer?!?!
+            Ok(0) => {
+                debug!("conn {id} socket closed");
+                break 'reader;
+            }
+            // An actual error happened! (oops)
+            Err(e) => {
+                warn!("conn {id} failed to read from socket: {e}");
+                break 'reader;
+            }
+            // This is the only thing we return
+            // from the match expression
+            Ok(num_bytes) => {
+                stats.bytes_read.fetch_add(num_bytes, Ordering::Relaxed);
+                frame.update(num_bytes)
+            }
+        };
+
+        // Step 2: get messages out of the frame (frame messages)
+        loop {
+            // Whatever is still buffered once the server shuts down is dropped,
+            // so nothing new reaches the rooms task while it winds down
+            if *shutdown.signal.borrow() {
+                break 'reader;
+            }
+            let mut payload = match frame.frame() {
+                FrameResult::Complete(payload) => payload,
+                FrameResult::Incomplete => break,
+                FrameResult::TooLong => {
+                    status(&sender, raw, "ERR toolong", "error: line too long").await;
+                    continue;
+                }
+            };
+
+            // JSON mode: from here on the command is handled like the line it stands for
+            if config.json {
+                payload = match WireCommand::to_line(&payload) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        status(&sender, raw, &format!("ERR {}", e.code()), &e.to_string()).await;
+                        continue;
+                    }
+                };
+            }
+
+            if !prompted {
+                prompted = true;
+                if payload != b"mode raw\n" {
+                    send_prompt(&sender).await;
+                }
+            }
+
+            // Keepalive, in either direction and whether or not there is a username yet
+            if payload == b"ping\n" {
+                let _ = sender.send(b"pong\n"[..].into()).await;
+                continue;
+            }
+            if payload == b"pong\n" {
+                awaiting_pong = false;
+                continue;
+            }
+
+            match &state {
+                State::Anon if payload == b"mode raw\n" => {
+                    raw = true;
+                    let _ = sender.send(b"OK mode raw\n"[..].into()).await;
+                }
+                // Step 3a: anonymous spectators skip the username and go straight to watching
+                State::Anon if config.anonymous_watchers > 0 && payload.starts_with(b"watch ") => {
+                    let spectator = Sender {
+                        inner: sender.clone(),
+                        id,
+                        username: "anonymous".into(),
+                        stats: stats.clone(),
+                        verbose: AtomicBool::new(false),
+                        echo: Arc::new(AtomicBool::new(false)),
+                        lagging: AtomicBool::new(false),
+                        muted: Default::default(),
+                        raw,
+                        admin: Default::default(),
+                        invisible: Default::default(),
+                    };
+                    let spectator = Arc::new(spectator);
+
+                    if shared.anonymous_connections.fetch_add(1, Ordering::Relaxed) >= config.anonymous_watchers {
+                        shared.anonymous_connections.fetch_sub(1, Ordering::Relaxed);
+                        spectator.status("ERR toomanyanonymous", "* too many anonymous connections, enter username");
+                        continue;
+                    }
+
+                    state = State::Spectator(spectator.clone());
+                    match Command::parse(payload) {
+                        Ok(command) => send_to_rooms(&room_sender, command, &spectator).await,
+                        Err(e) => spectator.status(&format!("ERR {}", e.code()), &e.to_string()),
+                    }
+                }
+                State::Spectator(sender) => match Command::parse(payload) {
+                    Ok(command @ (Command::Watch(_) | Command::Unwatch(_))) => {
+                        send_to_rooms(&room_sender, command, sender).await;
+                    }
+                    _ => sender.status("ERR readonly", "* anonymous connections are read-only"),
+                },
+                // Step 3a: pick up where a dropped connection left off
+                State::Anon if payload.starts_with(b"resume ") => {
+                    let token = String::from_utf8_lossy(&payload[7..]).trim().to_string();
+                    let (reply, session) = oneshot::channel();
+                    let _ = room_sender.send(Request::Resume { token, reply }).await;
+                    let Ok(Some(session)) = session.await else {
+                        status(&sender, raw, "ERR nosession", "* invalid or expired session, enter username").await;
+                        continue;
+                    };
+                    if !shared.claim_username(&session.username) {
+                        status(&sender, raw, "ERR taken", "username taken, choose another").await;
+                        continue;
+                    }
+                    let sender = Sender {
+                        inner: sender.clone(),
+                        id,
+                        username: session.username,
+                        stats: stats.clone(),
+                        verbose: AtomicBool::new(false),
+                        echo: Arc::new(AtomicBool::new(false)),
+                        lagging: AtomicBool::new(false),
+                        muted: Default::default(),
+                        raw,
+                        admin: Default::default(),
+                        invisible: Default::default(),
+                    };
+                    let sender = Arc::new(sender);
+
+                    sender.status("OK resume", &format!("* resumed as {}", sender.username));
+                    let _ = room_sender.send(Request::Connect(sender.clone())).await;
+                    for room in session.rooms {
+                        let _ = room_sender.send(Request::Command(Command::Join(room), sender.clone())).await;
+                    }
+                    allowed_commands = session.allowed_commands;
+                    session_token = Some(session.token);
+                    state = State::User(sender);
+                }
+                // Step 3: move from anon state to have a username
+                State::Anon => {
+                    payload.pop();
+
+                    // Strict mode: a command instead of a username is a protocol error
+                    let verb = payload.split(|b| *b == b' ').next().unwrap_or_default();
+                    if config.strict_username && Command::is_verb(verb) {
+                        status(&sender, raw, "ERR nousername", "protocol error: set username first").await;
+                        break 'reader;
+                    }
+
+                    let Ok(username) = String::from_utf8(payload) else {
+                        status(&sender, raw, "ERR invalidusername", "invalid username, must be valid UTF-8").await;
+                        continue;
+                    };
+                    let username = match validate_username(&username) {
+                        Ok(username) => username,
+                        Err(e) => {
+                            status(&sender, raw, "ERR invalidusername", e).await;
+                            continue;
+                        }
+                    };
+                    // Only claimed once the user has authenticated,
+                    // so connections that never do can't hold on to names
+                    let taken = match config.authenticator {
+                        Some(_) => shared.username_taken(&username),
+                        None => !shared.claim_username(&username),
+                    };
+                    if taken {
+                        status(&sender, raw, "ERR taken", "username taken, choose another").await;
+                        continue;
+                    }
+                    let sender = Sender {
+                        inner: sender.clone(),
+                        id,
+                        username,
+                        stats: stats.clone(),
+                        verbose: AtomicBool::new(false),
+                        echo: Arc::new(AtomicBool::new(false)),
+                        lagging: AtomicBool::new(false),
+                        muted: Default::default(),
+                        raw,
+                        admin: Default::default(),
+                        invisible: Default::default(),
+                    };
+
+                    let sender = Arc::new(sender);
+                    // Transition into the named state,
+                    // unless the user has to authenticate first
+                    if config.authenticator.is_some() {
+                        sender.status("OK username", "authenticate with auth <token>");
+                        state = State::Unauthenticated(sender);
+                    } else {
+                        let _ = room_sender.send(Request::Connect(sender.clone())).await;
+                        let token = new_session_token();
+                        sender.status(&format!("OK login {token}"), &format!("* session {token}"));
+                        session_token = Some(token);
+                        state = State::User(sender);
+                    }
+                }
+                State::Unauthenticated(sender) => {
+                    // Guessing tokens is limited like any other command
+                    if !command_rate.try_take() {
+                        sender.status("ERR ratelimited", "* rate limit exceeded, slow down");
+                        continue;
+                    }
+                    payload.pop();
+                    let Some(token) = payload.strip_prefix(b"auth ") else {
+                        sender.status("ERR unauthenticated", "authenticate first");
+                        continue;
+                    };
+                    let Some(authenticator) = &config.authenticator else { continue };
+
+                    let result = match std::str::from_utf8(token) {
+                        Ok(token) => authenticator.verify(&sender.username, token).await,
+                        Err(_) => AuthResult::Rejected,
+                    };
+                    match result {
+                        AuthResult::Accepted => {}
+                        // Service accounts are limited to a subset of the commands
+                        AuthResult::Restricted(allowed) => allowed_commands = Some(allowed),
+                        AuthResult::Rejected => {
+                            auth_failures += 1;
+                            if auth_failures >= MAX_AUTH_FAILURES {
+                                sender.status("ERR invalidtoken", "* invalid token, too many attempts");
+                                break 'reader;
+                            }
+                            sender.status("ERR invalidtoken", "* invalid token");
+                            continue;
+                        }
+                    }
+
+                    // Someone may have taken the name while this user was authenticating
+                    if !shared.claim_username(&sender.username) {
+                        allowed_commands = None;
+                        sender.status("ERR taken", "username taken, choose another");
+                        state = State::Anon;
+                        continue;
+                    }
+                    sender.confirm("* authenticated");
+                    let _ = room_sender.send(Request::Connect(sender.clone())).await;
+                    let token = new_session_token();
+                    sender.status(&format!("OK login {token}"), &format!("* session {token}"));
+                    session_token = Some(token);
+                    state = State::User(sender.clone());
+                }
+                State::User(sender) => {
+                    // Debug: echo back what the parser makes of the rest of the line
+                    if config.debug_commands && payload.starts_with(b"parse ") {
+                        let parsed = match Command::parse(payload.split_off(6)) {
+                            Ok(command) => format!("{command:?}"),
+                            Err(e) => e.to_string(),
+                        };
+                        sender.reply(&parsed);
+                        continue;
+                    }
+
+                    // Debug: what the frame is holding on to after this line
+                    if config.debug_commands && payload == b"framestate\n" {
+                        let (len, index, partial) = frame.state();
+                        sender.reply(&format!("* frame: buf {len} bytes, index {index}, {partial} bytes awaiting a newline"));
+                        continue;
+                    }
+
+                    // Terminal width, only used for soft-wrapping
+                    if payload.starts_with(b"width ") {
+                        payload.pop();
+                        let cols = std::str::from_utf8(&payload[6..]).ok().and_then(|cols| cols.parse().ok());
+                        match cols {
+                            Some(_) if !config.soft_wrap => sender.status("ERR disabled", "* soft wrap is disabled"),
+                            Some(cols) => {
+                                width.store(cols, Ordering::Relaxed);
+                                if sender.raw {
+                                    sender.reply("OK width");
+                                }
+                            }
+                            None => sender.status("ERR invalidargument", "* invalid width"),
+                        }
+                        continue;
+                    }
+
+                    // Step 4: parse message
+                    // Blank lines are ignored rather than reported
+                    if payload == b"\n" {
+                        continue;
+                    }
+                    let command = match Command::parse(payload) {
+                        Ok(command) => command,
+                        Err(e) => {
+                            sender.status(&format!("ERR {}", e.code()), &e.to_string());
+                            continue;
+                        }
+                    };
+                    debug!("conn {id} {command:?}");
+
+                    // Commands over the rate limit are dropped, with a notice in verbose mode
+                    if !command_rate.try_take() {
+                        match sender.raw {
+                            true => sender.reply("ERR ratelimited"),
+                            false => sender.notice("* rate limit exceeded, slow down"),
+                        }
+                        continue;
+                    }
+
+                    if allowed_commands.as_ref().is_some_and(|allowed| !allowed.contains(command.name())) {
+                        sender.status("ERR notallowed", &format!("* command not allowed: {}", command.name()));
+                        continue;
+                    }
+
+                    // In chars, so a message isn't cut short for not being ascii
+                    let longest = match &command {
+                        Command::Msg { msg, .. } | Command::Pm { msg, .. } | Command::Schedule { msg, .. } | Command::Broadcast(msg) => {
+                            msg.chars().count()
+                        }
+                        Command::Me { action, .. } => action.chars().count(),
+                        Command::MultiMsg { msgs, .. } => msgs.iter().map(|msg| msg.chars().count()).max().unwrap_or(0),
+                        _ => 0,
+                    };
+                    if longest > MAX_MESSAGE_LEN {
+                        sender.status("ERR toolong", "* message too long");
+                        continue;
+                    }
+
+                    let sent = match &command {
+                        Command::Msg { .. } | Command::Me { .. } | Command::Pm { .. } | Command::Broadcast(_) => 1,
+                        Command::MultiMsg { msgs, .. } => msgs.len(),
+                        _ => 0,
+                    };
+                    stats.messages_sent.fetch_add(sent, Ordering::Relaxed);
+                    shared.messages_routed.fetch_add(sent, Ordering::Relaxed);
+
+                    match command {
+                        Command::Schedule { room, delay, msg } => {
+                            scheduled.retain(|_, message| !message.handle.is_finished());
+                            if scheduled.len() >= MAX_SCHEDULED {
+                                sender.status("ERR toomanyscheduled", "* too many scheduled messages");
+                                continue;
+                            }
+
+                            let Some(send_at) = Instant::now().checked_add(Duration::from_secs(delay)) else {
+                                sender.status("ERR invalidargument", "* invalid delay");
+                                continue;
+                            };
+                            let id = next_schedule_id;
+                            next_schedule_id += 1;
+
+                            let preview = match msg.char_indices().nth(SCHEDULE_PREVIEW_LEN) {
+                                Some((end, _)) => format!("{}...", &msg[..end]),
+                                None => msg.clone(),
+                            };
+                            let scheduled_room = room.clone();
+
+                            let task = tokio::spawn({
+                                let room_sender = room_sender.clone();
+                                let shared = shared.clone();
+                                let sender = scheduled_as.get_or_insert_with(|| watch::channel(sender.clone()).0).subscribe();
+                                async move {
+                                    tokio::time::sleep_until(send_at.into()).await;
+                                    let sender = sender.borrow().clone();
+                                    sender.stats.messages_sent.fetch_add(1, Ordering::Relaxed);
+                                    shared.messages_routed.fetch_add(1, Ordering::Relaxed);
+                                    send_to_rooms(&room_sender, Command::Msg { room, msg }, &sender).await;
+                                }
+                            });
+                            let message = ScheduledMessage {
+                                room: scheduled_room,
+                                preview,
+                                send_at,
+                                handle: task.abort_handle(),
+                            };
+                            scheduled.insert(id, message);
+                            sender.status(&format!("OK schedule {id}"), &format!("* scheduled message {id} in {delay}s"));
+                        }
+                        // Typing notices over the rate limit are dropped (with a notice
+                        // in verbose mode), the next one will get through
+                        Command::Typing(_) if last_typing.is_some_and(|at| at.elapsed() < TYPING_INTERVAL) => match sender.raw {
+                            true => sender.reply("ERR ratelimited"),
+                            false => sender.notice("* typing notice dropped: rate limited"),
+                        },
+                        Command::Typing(room) => {
+                            last_typing = Some(Instant::now());
+                            send_to_rooms(&room_sender, Command::Typing(room), sender).await;
+                        }
+                        Command::Verbose(on) => {
+                            sender.verbose.store(on, Ordering::Relaxed);
+                            sender.status("OK verbose", if on { "* verbose on" } else { "* verbose off" });
+                        }
+                        Command::Echo(on) => {
+                            sender.echo.store(on, Ordering::Relaxed);
+                            sender.status("OK echo", if on { "* echo on" } else { "* echo off" });
+                        }
+                        // Only hides the user from `isonline`, not from the rooms they are in
+                        Command::Invisible(on) => {
+                            sender.invisible.store(on, Ordering::Relaxed);
+                            sender.status("OK invisible", if on { "* invisible on" } else { "* invisible off" });
+                        }
+                        Command::FrameTest => {
+                            for frame in frametest_frames() {
+                                let _ = sender.inner.send(frame.into()).await;
+                            }
+                            if sender.raw {
+                                sender.reply("OK frametest");
+                            }
+                        }
+                        // Lives in the frame, which only the reader has
+                        Command::BufLimit(limit) => {
+                            if !sender.admin.load(Ordering::Relaxed) {
+                                sender.reject(Rejection::NotAdmin);
+                                continue;
+                            }
+                            if let Some(limit) = limit {
+                                frame.set_max_line(limit);
+                            }
+                            let limit = frame.max_line();
+                            sender.status(&format!("OK buflimit {limit}"), &format!("* lines up to {limit} bytes"));
+                        }
+                        Command::Help => {
+                            let _ = sender.inner.send(HELP.as_bytes().into()).await;
+                            if sender.raw {
+                                sender.reply("OK help");
+                            }
+                        }
+                        // Leaving the loop cleans up the same way as the socket closing,
+                        // the writer closes the connection once it has sent the goodbye
+                        Command::Quit => {
+                            sender.status("OK quit", "* goodbye");
+                            break 'reader;
+                        }
+                        // Lists pending scheduled messages, oldest first.
+                        // The ids are the ones taken by `cancelschedule`
+                        Command::Schedules => {
+                            scheduled.retain(|_, message| !message.handle.is_finished());
+                            let mut ids: Vec<_> = scheduled.keys().copied().collect();
+                            ids.sort();
+
+                            sender.reply(&format!("* {} scheduled messages", ids.len()));
+                            for id in ids {
+                                let message = &scheduled[&id];
+                                let secs = message.send_at.saturating_duration_since(Instant::now()).as_secs();
+                                let line = format!("* {id}: {} in {secs}s: {}", message.room, message.preview);
+                                sender.reply(&line);
+                            }
+                            if sender.raw {
+                                sender.reply("OK schedules");
+                            }
+                        }
+                        Command::CancelSchedule(id) => match scheduled.remove(&id) {
+                            Some(message) if !message.handle.is_finished() => {
+                                message.handle.abort();
+                                sender.status("OK cancelschedule", &format!("* cancelled scheduled message {id}"));
+                            }
+                            _ => sender.status("ERR nosuchschedule", &format!("* no such scheduled message {id}")),
+                        },
+                        // `Sender` can't change once shared, so a new one is made with the new name.
+                        // Everything after this is sent with it
+                        Command::Nick(nick) => {
+                            let nick = match validate_username(&nick) {
+                                Ok(nick) => nick,
+                                Err(e) => {
+                                    sender.status("ERR invalidusername", &format!("* {e}"));
+                                    continue;
+                                }
+                            };
+                            if !shared.claim_username(&nick) {
+                                sender.status("ERR taken", "* username taken, choose another");
+                                continue;
+                            }
+                            shared.release_username(&sender.username);
+                            let renamed = Arc::new(Sender {
+                                inner: sender.inner.clone(),
+                                id,
+                                username: nick.clone(),
+                                stats: stats.clone(),
+                                verbose: AtomicBool::new(sender.verbose.load(Ordering::Relaxed)),
+                                echo: sender.echo.clone(),
+                                lagging: AtomicBool::new(sender.lagging.load(Ordering::Relaxed)),
+                                muted: sender.muted.clone(),
+                                raw: sender.raw,
+                                admin: sender.admin.clone(),
+                                invisible: sender.invisible.clone(),
+                            });
+                            let _ = room_sender.send(Request::Command(Command::Nick(nick), renamed.clone())).await;
+                            if let Some(scheduled_as) = &scheduled_as {
+                                scheduled_as.send_replace(renamed.clone());
+                            }
+                            state = State::User(renamed);
+                        }
+                        // Step 5: send message to rooms
+                        command => send_to_rooms(&room_sender, command, sender).await,
+                    }
+                }
+            }
+        }
+    }
+
+    // Closes the connection once the writer is done with its half too,
+    // which matters for TLS where both halves share one stream
+    drop(reader);
+
+    // Scheduled messages don't outlive the connection
+    for message in scheduled.values() {
+        message.handle.abort();
+    }
+
+    match &state {
+        State::User(sender) => {
+            let session = session_token.map(|token| Session {
+                token,
+                username: sender.username.clone(),
+                rooms: vec![],
+                allowed_commands,
+                expires: Instant::now() + config.session_ttl,
+            });
+            let _ = room_sender.send(Request::Disconnect(sender.clone(), session)).await;
+        }
+        State::Spectator(sender) => {
+            let _ = room_sender.send(Request::Disconnect(sender.clone(), None)).await;
+        }
+        _ => {}
+    }
+
+    // Unauthenticated users never claimed their name
+    if let State::User(sender) = &state {
+        shared.release_username(&sender.username);
+    }
+
+    if let State::Spectator(_) = state {
+        shared.anonymous_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// -----------------------------------------------------------------------------
+//   - Frame test -
+//   Known tricky frames for clients to test their own framing against.
+//   Sent in response to `frametest`, in this order, each as a separate write:
+//   1. `* frametest begin\n`
+//   2. `* split ` and 3. `line\n`: a single line in two writes
+//   4. `* crlf line\r\n`: a line ending in CRLF (the \r is part of the line)
+//   5. FRAMETEST_LINE_LEN bytes, newline included: `*`, then `x`s, then `\n`
+//   6. `* frametest end\n`
+// -----------------------------------------------------------------------------
+// The longest line the server takes from clients, see MAX_LINE
+const FRAMETEST_LINE_LEN: usize = MAX_LINE;
+
+fn frametest_frames() -> Vec<Arc<[u8]>> {
+    let mut long_line = vec![b'x'; FRAMETEST_LINE_LEN];
+    long_line[0] = b'*';
+    long_line[FRAMETEST_LINE_LEN - 1] = b'\n';
+
+    vec![
+        Arc::from(&b"* frametest begin\n"[..]),
+        Arc::from(&b"* split "[..]),
+        Arc::from(&b"line\n"[..]),
+        Arc::from(&b"* crlf line\r\n"[..]),
+        long_line.into(),
+        Arc::from(&b"* frametest end\n"[..]),
+    ]
+}
+
+// -----------------------------------------------------------------------------
+//   - Soft wrap -
+//   Break lines so no line is longer than `width` chars.
+//   Lines are broken between words where possible,
+//   words longer than a whole line are split.
+// -----------------------------------------------------------------------------
+fn soft_wrap(text: &str, width: usize) -> String {
+    let mut wrapped = String::with_capacity(text.len() + text.len() / width + 1);
+
+    for line in text.split_inclusive('\n') {
+        let mut column = 0;
+        for word in line.split_inclusive(' ') {
+            let len = word.trim_end().chars().count();
+            if column > 0 && column + len > width {
+                // Replace the space after the previous word with the line break
+                if wrapped.ends_with(' ') {
+                    wrapped.pop();
+                }
+                wrapped.push('\n');
+                column = 0;
+            }
+
+            for c in word.chars() {
+                if column == width && c != ' ' && c != '\n' {
+                    wrapped.push('\n');
+                    column = 0;
+                }
+                wrapped.push(c);
+                column += 1;
+            }
+        }
+    }
+
+    wrapped
+}
+
+async fn handle_writer<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    id: usize,
+    mut receiver: Receiver<Outgoing>,
+    stats: Arc<ConnectionStats>,
+    width: Arc<AtomicUsize>,
+    config: Arc<Config>,
+    mut shutdown: Shutdown,
+) {
+    let mut shutting_down = false;
+    loop {
+        let message = tokio::select! {
+            message = receiver.recv() => message,
+            // Stop taking new messages, but still send the ones already queued
+            _ = shutdown.signal.changed(), if !shutting_down => {
+                shutting_down = true;
+                receiver.close();
+                continue;
+            }
+        };
+        let Some(message) = message else { break };
+
+        // Messages are shared between all recipients,
+        // so wrapping needs a buffer of its own
+        let width = width.load(Ordering::Relaxed);
+        let wrapped = match std::str::from_utf8(&message.bytes) {
+            _ if config.json => Some(WireEvent::encode(&message)),
+            Ok(text) if width > 0 && text.len() > width => Some(soft_wrap(text, width).into_bytes()),
+            _ => None,
+        };
+        let bytes = wrapped.as_deref().unwrap_or(&message.bytes);
+
+        // The client is gone. Returning drops the receiver, so anyone sending
+        // to this connection (e.g. its `forward_room` tasks) gets an error instead
+        if let Err(e) = writer.write_all(bytes).await {
+            warn!("conn {id} failed to write to socket: {e}");
+            break;
+        }
+        if let Err(e) = writer.flush().await {
+            warn!("conn {id} failed to flush socket: {e}");
+            break;
+        }
+        stats.messages_received.fetch_add(1, Ordering::Relaxed);
+        stats.bytes_written.fetch_add(message.bytes.len(), Ordering::Relaxed);
+    }
+
+    // The readers stop at shutdown too, so the channel can close before the signal is seen here
+    if shutting_down || *shutdown.signal.borrow() {
+        let goodbye = Outgoing::from(&b"* server shutting down\n"[..]);
+        let goodbye = if config.json { WireEvent::encode(&goodbye) } else { goodbye.bytes.to_vec() };
+        let _ = writer.write_all(&goodbye).await;
+    }
+    // Sends the TLS close_notify, and the FIN for plaintext
+    let _ = writer.shutdown().await;
+}
+
+// Plaintext and TLS connections only differ in the type of the two halves.
+// Connections are logged at info as they come and go, what goes wrong with
+// them (e.g. socket errors) at warn and every command at debug.
+async fn handle_connection<R, W>(
+    reader: R,
+    writer: W,
+    room_sender: RoomSender,
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    shutdown: Shutdown,
+) where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    state.total_connections.fetch_add(1, Ordering::Relaxed);
+    info!("conn {id} connected");
+
+    let (sender, receiver) = mpsc::channel(config.writer_capacity);
+    // let sender = Arc::new(Sender { inner: sender, id, username: String::new() });
+    let stats = Arc::new(ConnectionStats::new());
+    // Terminal width of the client, zero if unknown
+    let width = Arc::new(AtomicUsize::new(0));
+
+    tokio::spawn({
+        let stats = stats.clone();
+        let width = width.clone();
+        let config = config.clone();
+        let shutdown = shutdown.clone();
+        async move { handle_writer(writer, id, receiver, stats, width, config, shutdown).await }
+    });
+
+    if config.echo {
+        tokio::spawn(async move {
+            handle_echo(reader, sender, new_framing(&config), shutdown).await;
+            state.active_connections.fetch_sub(1, Ordering::Relaxed);
+            info!("conn {id} disconnected");
+        });
+        return;
+    }
+
+    tokio::spawn(async move {
+        handle_reader(reader, sender, id, room_sender, config, stats, width, state.clone(), shutdown).await;
+        state.active_connections.fetch_sub(1, Ordering::Relaxed);
+        info!("conn {id} disconnected");
+    });
+}
+
+// -----------------------------------------------------------------------------
+//   - Shutdown -
+//   On ctrl-c the server stops accepting connections and every writer sends
+//   what it still has queued, followed by a goodbye line. Readers stop reading
+//   and leave their rooms, then the connection is closed. Once the last
+//   connection is gone the rooms task ends, and `Server::run` returns.
+// -----------------------------------------------------------------------------
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+struct Shutdown {
+    signal: watch::Receiver<bool>,
+    // Never used, `Server::run` waits for the last of these to be dropped
+    _done: mpsc::Sender<()>,
+}
+
+// -----------------------------------------------------------------------------
+//   - Echo mode -
+//   For client authors: input is framed exactly like the chat server
+//   frames it, and every complete line is sent straight back.
+//   No username, no commands, no rooms.
+// -----------------------------------------------------------------------------
+async fn handle_echo<R: AsyncRead + Unpin>(
+    mut reader: R,
+    sender: mpsc::Sender<Outgoing>,
+    mut frame: Box<dyn Framing>,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        let read = tokio::select! {
+            read = reader.read(frame.unfilled()) => read,
+            Ok(_) = shutdown.signal.wait_for(|shutting_down| *shutting_down) => break,
+        };
+        match read {
+            Ok(0) | Err(_) => break,
+            Ok(num_bytes) => frame.update(num_bytes),
+        }
+
+        loop {
+            let line = match frame.frame() {
+                FrameResult::Complete(line) => line,
+                FrameResult::Incomplete => break,
+                FrameResult::TooLong => b"error: line too long\n".to_vec(),
+            };
+            if sender.send(line.into()).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//   - Health -
+//   A separate listener for load balancers:
+//   every connection gets a single `OK <current>/<max>` line and is closed.
+//   No framing, no username, no rooms.
+// -----------------------------------------------------------------------------
+async fn health(listener: TcpListener, state: Arc<ServerState>, max_connections: usize) {
+    loop {
+        let (mut stream, _addr) = accept_retrying(|| listener.accept()).await;
+        let current = state.active_connections.load(Ordering::Relaxed);
+        let status = format!("OK {current}/{max_connections}\n");
+        tokio::spawn(async move {
+            let _ = stream.write_all(status.as_bytes()).await;
+        });
+    }
+}
+
+// -----------------------------------------------------------------------------
+//   - Token bucket -
+//   Holds up to `capacity` tokens and refills at `rate` tokens per second.
+//   Used to smooth out bursts of new connections,
+//   e.g. every client reconnecting at once after a restart,
+//   and to stop a single connection from flooding the server.
+// -----------------------------------------------------------------------------
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        Self::per(rate, Duration::from_secs(1))
+    }
+
+    // Up to `capacity` tokens every `period`
+    fn per(capacity: u32, period: Duration) -> Self {
+        let capacity = f64::from(capacity.max(1));
+        Self {
+            rate: capacity / period.as_secs_f64(),
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = Instant::now();
+    }
+
+    // Take a token if there is one, without waiting
+    fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    // Wait for a token to become available and take it
+    async fn take(&mut self) {
+        self.refill();
+        if self.tokens < 1.0 {
+            let wait = (1.0 - self.tokens) / self.rate;
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+            self.refill();
+        }
+        self.tokens -= 1.0;
+    }
+}
+
+/// A bound server, ready to `run`.
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// let server = chattery::Server::bind(chattery::Config::default()).await?;
+/// let shutdown = server.shutdown_handle();
+/// tokio::spawn(async move {
+///     let _ = tokio::signal::ctrl_c().await;
+///     shutdown.shutdown();
+/// });
+/// server.run().await
+/// # }
+/// ```
+pub struct Server {
+    config: Arc<Config>,
+    listener: Option<TcpListener>,
+    #[cfg(unix)]
+    unix_listener: Option<UnixListener>,
+    health_listener: Option<TcpListener>,
+    tls: Option<TlsAcceptor>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+/// Tells a running `Server` to shut down, see `Server::shutdown_handle`
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    /// Stops accepting connections. Every connection is sent what is still
+    /// queued for it and a goodbye line and is closed, after which
+    /// `Server::run` returns.
+    pub fn shutdown(&self) {
+        let _ = self.0.send(true);
+    }
+}
+
+impl Server {
+    /// Binds `config.bind` (unless `config.tcp` is false), the unix socket
+    /// and the health check, whichever of them are configured
+    pub async fn bind(config: Config) -> std::io::Result<Self> {
+        let listener = match config.tcp {
+            true => Some(bind(config.bind)?),
+            false => None,
+        };
+        Self::with_listener(config, listener).await
+    }
+
+    /// Like `bind`, but serves TCP connections from a listener that is
+    /// already bound (e.g. to port 0 so the OS picks a free port),
+    /// whatever `config.bind` and `config.tcp` say
+    pub async fn from_listener(config: Config, listener: TcpListener) -> std::io::Result<Self> {
+        Self::with_listener(config, Some(listener)).await
+    }
+
+    async fn with_listener(config: Config, listener: Option<TcpListener>) -> std::io::Result<Self> {
+        let tls = config.tls.as_ref().map(TlsFiles::load).transpose()?;
+        #[cfg(unix)]
+        let unix_listener = config.unix.as_deref().map(bind_unix).transpose()?;
+        #[cfg(not(unix))]
+        if config.unix.is_some() {
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "unix sockets are only supported on unix"));
+        }
+        let health_listener = match config.health {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+        Ok(Self {
+            config: Arc::new(config),
+            listener,
+            #[cfg(unix)]
+            unix_listener,
+            health_listener,
+            tls,
+            shutdown: Arc::new(watch::channel(false).0),
+        })
+    }
+
+    /// The address TCP connections are accepted on, if there is one
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// The address of the health check, if there is one
+    pub fn health_addr(&self) -> Option<SocketAddr> {
+        self.health_listener.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
+    }
+
+    /// Serves connections until shut down with a `ShutdownHandle`
+    pub async fn run(self) -> std::io::Result<()> {
+        let Self { config, listener, health_listener, tls, shutdown: shutdown_sender, .. } = self;
+        #[cfg(unix)]
+        let unix_listener = self.unix_listener;
+        let state = Arc::new(ServerState::default());
+        if let Some(health_listener) = health_listener {
+            let mut signal = shutdown_sender.subscribe();
+            let (state, max_connections) = (state.clone(), config.max_connections);
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = health(health_listener, state, max_connections) => {}
+                    _ = signal.wait_for(|shutting_down| *shutting_down) => {}
+                }
+            });
+        }
+        // Setup rooms here
+        let (room_sender, room_receiver) = mpsc::channel(1_000);
+        let rooms_task = tokio::spawn({
+            let config = config.clone();
+            let requests = room_sender.downgrade();
+            let state = state.clone();
+            let signal = shutdown_sender.subscribe();
+            async move { rooms(room_receiver, requests, config, state, signal).await }
+        });
+
+        let mut signal = shutdown_sender.subscribe();
+        let (done, mut all_done) = mpsc::channel::<()>(1);
+        let shutdown = Shutdown { signal: signal.clone(), _done: done };
+
+        let mut accept_rate = TokenBucket::new(config.accept_rate);
+        info!("accepting up to {} connections", config.max_connections);
+        loop {
+            let stream = tokio::select! {
+                _ = signal.wait_for(|shutting_down| *shutting_down) => break,
+                stream = async {
+                    // Connections over the accept rate wait in the listen backlog
+                    accept_rate.take().await;
+                    accept_retrying(|| {
+                        accept(
+                            listener.as_ref(),
+                            #[cfg(unix)]
+                            unix_listener.as_ref(),
+                        )
+                    })
+                    .await
+                } => stream,
+            };
+            // Turn the connection away straight away if the server is full
+            if state.active_connections.fetch_add(1, Ordering::Relaxed) >= config.max_connections {
+                state.active_connections.fetch_sub(1, Ordering::Relaxed);
+                warn!("server full, dropping connection");
+                turn_away(stream, &config);
+                continue;
+            }
+            let stream = match stream {
+                Incoming::Tcp(stream) => {
+                    // Lines are small and should go out as soon as they are written,
+                    // not wait for the client to acknowledge the previous one
+                    let _ = stream.set_nodelay(true);
+                    stream
+                }
+                #[cfg(unix)]
+                Incoming::Unix(stream) => {
+                    let (reader, writer) = stream.into_split();
+                    handle_connection(reader, writer, room_sender.clone(), config.clone(), state.clone(), shutdown.clone()).await;
+                    continue;
+                }
+            };
+            let Some(acceptor) = tls.clone() else {
+                let (reader, writer) = stream.into_split();
+                handle_connection(reader, writer, room_sender.clone(), config.clone(), state.clone(), shutdown.clone()).await;
+                continue;
+            };
+            // The handshake happens in its own task so a slow client can't hold up accepting others
+            let (room_sender, config, state, mut shutdown) = (room_sender.clone(), config.clone(), state.clone(), shutdown.clone());
+            tokio::spawn(async move {
+                let handshake = tokio::select! {
+                    handshake = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)) => handshake,
+                    Ok(_) = shutdown.signal.wait_for(|shutting_down| *shutting_down) => {
+                        state.active_connections.fetch_sub(1, Ordering::Relaxed);
+                        return;
+                    }
+                };
+                match handshake {
+                    Ok(Ok(stream)) => {
+                        let (reader, writer) = tokio::io::split(stream);
+                        handle_connection(reader, writer, room_sender, config, state, shutdown).await;
+                    }
+                    Ok(Err(e)) => {
+                        warn!("tls handshake failed: {e}");
+                        state.active_connections.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    Err(_) => {
+                        warn!("tls handshake timed out");
+                        state.active_connections.fetch_sub(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+
+        info!("shutting down");
+        drop(shutdown);
+        // Every reader and writer holds a `Shutdown`, so this returns once they are all done
+        let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, all_done.recv()).await;
+        // The readers had the other senders, so the rooms task ends with this one
+        drop(room_sender);
+        let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, rooms_task).await;
+        if let Some(path) = &config.unix {
+            let _ = std::fs::remove_file(path);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_log_rotates() {
+        let path = std::env::temp_dir().join(format!("chattery-history-{}.log", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        let (writer, receiver) = mpsc::channel(100);
+        for i in 0..20 {
+            let line = format!("alice: message {i}");
+            writer.try_send(LoggedMessage { time: 0, room: "lobby".into(), line }).unwrap();
+        }
+        drop(writer);
+        // Room for about 10 messages per file
+        HistoryLog::write(path.clone(), file, 600, receiver);
+
+        let current = std::fs::metadata(&path).unwrap().len();
+        let rotated = std::fs::metadata(rotated_path(&path)).unwrap().len();
+        assert!(current <= 600 && rotated <= 600, "{current} {rotated}");
+        let restored = HistoryLog::read(&path, 3).unwrap();
+        let lobby: Vec<_> = restored["lobby"].iter().map(|line| String::from_utf8_lossy(line).into_owned()).collect();
+        assert_eq!(lobby, ["alice: message 17\n", "alice: message 18\n", "alice: message 19\n"]);
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(rotated_path(&path));
+    }
+
+    // What a read from the socket would do
+    fn feed(frame: &mut dyn Framing, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let unfilled = frame.unfilled();
+            let len = unfilled.len().min(bytes.len());
+            unfilled[..len].copy_from_slice(&bytes[..len]);
+            frame.update(len);
+            bytes = &bytes[len..];
+        }
+    }
+
+    fn length_prefixed(payload: &[u8]) -> Vec<u8> {
+        let mut message = (payload.len() as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(payload);
+        message
+    }
+
+    #[test]
+    fn lines_and_partial_in_one_read() {
+        let mut frame = Frame::new();
+        feed(&mut frame, b"join a\njoin b\npar");
+        assert!(matches!(frame.frame(), FrameResult::Complete(line) if line == b"join a\n"));
+        assert!(matches!(frame.frame(), FrameResult::Complete(line) if line == b"join b\n"));
+        assert!(matches!(frame.frame(), FrameResult::Incomplete));
+        assert_eq!(frame.state().2, 3);
+        feed(&mut frame, b"t a\n");
+        assert!(matches!(frame.frame(), FrameResult::Complete(line) if line == b"part a\n"));
+    }
+
+    #[test]
+    fn length_header_across_reads() {
+        let mut frame = LengthFrame::new();
+        let message = length_prefixed(b"join rust");
+        for byte in &message[..3] {
+            feed(&mut frame, &[*byte]);
+            assert!(matches!(frame.frame(), FrameResult::Incomplete));
+        }
+        feed(&mut frame, &message[3..]);
+        assert!(matches!(frame.frame(), FrameResult::Complete(line) if line == b"join rust\n"));
+        assert!(matches!(frame.frame(), FrameResult::Incomplete));
+    }
+
+    #[test]
+    fn length_payload_across_three_reads() {
+        let mut frame = LengthFrame::new();
+        let payload = b"msg rust one line\nand another".repeat(100);
+        let mut message = length_prefixed(&payload);
+        // The start of the next message comes with the last read
+        let next = length_prefixed(b"part rust");
+        message.extend_from_slice(&next[..6]);
+        let third = message.len() / 3;
+        feed(&mut frame, &message[..third]);
+        assert!(matches!(frame.frame(), FrameResult::Incomplete));
+        feed(&mut frame, &message[third..2 * third]);
+        assert!(matches!(frame.frame(), FrameResult::Incomplete));
+        feed(&mut frame, &message[2 * third..]);
+        let FrameResult::Complete(line) = frame.frame() else { panic!("no message") };
+        assert_eq!(line[..payload.len()], payload[..]);
+        assert!(matches!(frame.frame(), FrameResult::Incomplete));
+        feed(&mut frame, &next[6..]);
+        assert!(matches!(frame.frame(), FrameResult::Complete(line) if line == b"part rust\n"));
+    }
+
+    #[test]
+    fn length_too_long_is_skipped() {
+        let mut frame = LengthFrame::new();
+        feed(&mut frame, &length_prefixed(&vec![b'a'; MAX_LINE + 1]));
+        assert!(matches!(frame.frame(), FrameResult::TooLong));
+        feed(&mut frame, &length_prefixed(b"list"));
+        assert!(matches!(frame.frame(), FrameResult::Complete(line) if line == b"list\n"));
+    }
+
+    #[test]
+    fn compressed_history_is_intact() {
+        let long: Arc<[u8]> = format!("alice: {}\n", "all work and no play ".repeat(50)).into_bytes().into();
+        let stored = StoredLine::new(long.clone(), Some(64));
+        assert!(matches!(&stored, StoredLine::Deflated(deflated) if deflated.len() < long.len()));
+        assert_eq!(stored.bytes(), long);
+
+        // Too short to bother
+        let short: Arc<[u8]> = Arc::from(&b"alice: hi\n"[..]);
+        assert!(matches!(StoredLine::new(short.clone(), Some(64)), StoredLine::Plain(bytes) if bytes == short));
+    }
+
+    #[test]
+    fn schedule_delay_is_capped() {
+        let parsed = Command::parse(b"schedule lobby 60 hi\n".to_vec());
+        assert!(matches!(parsed, Ok(Command::Schedule { delay: 60, .. })));
+        let parsed = Command::parse(format!("schedule lobby {MAX_SCHEDULE_DELAY} hi\n").into_bytes());
+        assert!(matches!(parsed, Ok(Command::Schedule { .. })));
+        let parsed = Command::parse(format!("schedule lobby {} hi\n", MAX_SCHEDULE_DELAY + 1).into_bytes());
+        assert!(matches!(parsed, Err(ParseError::InvalidArgument)));
+        let parsed = Command::parse(format!("schedule lobby {} hi\n", u64::MAX).into_bytes());
+        assert!(matches!(parsed, Err(ParseError::InvalidArgument)));
+    }
+
+    #[test]
+    fn wire_commands_round_trip() {
+        let commands = [
+            (r#"{"cmd":"login","name":"alice"}"#, "alice"),
+            (r#"{"cmd":"auth","token":"hunter2"}"#, "auth hunter2"),
+            (r#"{"cmd":"join","room":"lobby"}"#, "join lobby"),
+            (r#"{"cmd":"part","room":"lobby"}"#, "part lobby"),
+            (r#"{"cmd":"msg","room":"lobby","text":"hi there"}"#, "msg lobby hi there"),
+            (r#"{"cmd":"me","room":"lobby","text":"waves"}"#, "me lobby waves"),
+            (r#"{"cmd":"pm","to":"bob","text":"hi there"}"#, "pm bob hi there"),
+            (r#"{"cmd":"nick","name":"alicia"}"#, "nick alicia"),
+            (r#"{"cmd":"who","room":"lobby"}"#, "who lobby"),
+            (r#"{"cmd":"topic","room":"lobby"}"#, "topic lobby"),
+            (r#"{"cmd":"topic","room":"lobby","text":"rust"}"#, "topic lobby rust"),
+            (r#"{"cmd":"watch","room":"lobby"}"#, "watch lobby"),
+            (r#"{"cmd":"unwatch","room":"lobby"}"#, "unwatch lobby"),
+            (r#"{"cmd":"list"}"#, "list"),
+            (r#"{"cmd":"help"}"#, "help"),
+            (r#"{"cmd":"quit"}"#, "quit"),
+            (r#"{"cmd":"ping"}"#, "ping"),
+            (r#"{"cmd":"pong"}"#, "pong"),
+            (r#"{"cmd":"resume","token":"abc"}"#, "resume abc"),
+        ];
+        for (json, expected) in commands {
+            let line = WireCommand::to_line(json.as_bytes()).unwrap();
+            assert_eq!(line, format!("{expected}\n").into_bytes(), "{json}");
+            // Logins, auth, resume, pings and pongs are handled before a line gets parsed as a command
+            let word = expected.split(' ').next().unwrap();
+            if !matches!(word, "alice" | "auth" | "resume" | "ping" | "pong") {
+                assert!(Command::parse(line).is_ok(), "{json}");
+            }
+        }
+    }
+
+    #[test]
+    fn wire_command_fields_are_single_words() {
+        let rejected = [
+            (r#"{"cmd":"msg","room":"a b","text":"hi"}"#, ParseError::RoomHasWhitespace),
+            (r#"{"cmd":"join","room":"a\tb"}"#, ParseError::RoomHasWhitespace),
+            (r#"{"cmd":"join","room":""}"#, ParseError::MissingRoom),
+            (r#"{"cmd":"pm","to":"bob alice","text":"hi"}"#, ParseError::InvalidArgument),
+            (r#"{"cmd":"nick","name":"a b"}"#, ParseError::InvalidArgument),
+            (r#"{"cmd":"auth","token":""}"#, ParseError::MissingArgument),
+            (r#"{"cmd":"msg","room":"lobby","text":"hi\nquit"}"#, ParseError::InvalidArgument),
+        ];
+        for (json, error) in rejected {
+            let result = WireCommand::to_line(json.as_bytes());
+            assert_eq!(result.map_err(|e| e.code()), Err(error.code()), "{json}");
+        }
+    }
+
+    #[test]
+    fn wire_events_carry_the_room() {
+        let message = Outgoing { room: Some("lobby".into()), seq: Some(3), bytes: Arc::from(&b"[12:34:05] alice: hi\n"[..]) };
+        let encoded = String::from_utf8(WireEvent::encode(&message)).unwrap();
+        let expected = r#"{"type":"msg","time":"12:34:05","room":"lobby","seq":3,"from":"alice","text":"hi"}"#;
+        assert_eq!(encoded, format!("{expected}\n"));
+
+        let message = Outgoing::from(&b"* server shutting down\n"[..]);
+        let encoded = String::from_utf8(WireEvent::encode(&message)).unwrap();
+        assert_eq!(encoded, "{\"type\":\"notice\",\"text\":\"server shutting down\"}\n");
+    }
+
+    #[test]
+    fn parse_edge_cases_dont_panic() {
+        for line in [&b""[..], b"\n", b" \n", b"msg\n", b"msg \n", b"msg  \n", b"pm \n", b"topic  \n", b"react lobby 1\n", b"switch a\n"] {
+            assert!(Command::parse(line.to_vec()).is_err(), "{line:?}");
+        }
+        assert!(matches!(Command::parse(b"msg  hi\n".to_vec()), Err(ParseError::MissingRoom)));
+    }
+
+    #[test]
+    fn usernames_are_trimmed_and_validated() {
+        assert_eq!(validate_username("  alice \n"), Ok("alice".into()));
+        assert!(validate_username("").is_err());
+        assert!(validate_username(" \t ").is_err());
+        assert_eq!(validate_username(&"a".repeat(MAX_USERNAME_LEN)), Ok("a".repeat(MAX_USERNAME_LEN)));
+        assert!(validate_username(&"a".repeat(40)).is_err());
+        assert!(validate_username("ali\tce").is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let parse = |line: &[u8]| Command::parse(line.to_vec()).map(|_| ()).map_err(|e| e.code());
+        assert_eq!(parse(b"\xff\xfe\n"), Err("notutf8"));
+        assert_eq!(parse(b"frobnicate lobby\n"), Err("unknowncommand"));
+        assert_eq!(parse(b"join\n"), Err("missingroom"));
+        assert_eq!(parse(b"join \n"), Err("missingroom"));
+        assert_eq!(parse(b"msg lobby\n"), Err("missingmessage"));
+        assert_eq!(parse(b"nick\n"), Err("missingargument"));
+        assert_eq!(parse(b"nick al ice\n"), Err("invalidargument"));
+        assert_eq!(parse(b"join lob by\n"), Err("roomwhitespace"));
+        assert_eq!(parse(b"msg lobby hi\n"), Ok(()));
+    }
+
+    #[test]
+    fn bind_addresses() {
+        assert_eq!(parse_bind("0.0.0.0:9000"), Ok(SocketAddr::from(([0, 0, 0, 0], 9000))));
+        assert_eq!(parse_bind("[::1]:9000"), Ok("[::1]:9000".parse().unwrap()));
+        assert!(parse_bind("127.0.0.1").is_err());
+        assert!(parse_bind("not an address").is_err());
+    }
+
+    #[test]
+    fn word_filter_modes() {
+        let words: HashSet<String> = ["darn".into()].into();
+        let reject = WordFilter { words: words.clone(), mode: FilterMode::Reject };
+        assert_eq!(reject.apply("well DARN it"), None);
+        // Only whole words
+        assert_eq!(reject.apply("darned darnation"), Some("darned darnation".into()));
+
+        let censor = WordFilter { words, mode: FilterMode::Censor };
+        assert_eq!(censor.apply("darn, Darn!darn"), Some("****, ****!****".into()));
+        assert_eq!(censor.apply("undarned"), Some("undarned".into()));
+    }
+
+    // A `Shutdown` for a single connection, shut down with the sender
+    fn shutdown() -> (watch::Sender<bool>, Shutdown) {
+        let (signal, receiver) = watch::channel(false);
+        let (done, _) = mpsc::channel(1);
+        (signal, Shutdown { signal: receiver, _done: done })
+    }
+
+    #[tokio::test]
+    async fn reader_over_duplex() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (sender, _replies) = mpsc::channel(16);
+        let (room_sender, mut requests) = mpsc::channel(16);
+        let config = Arc::new(Config::default());
+        let stats = Arc::new(ConnectionStats::new());
+        let (_signal, shutdown) = shutdown();
+        tokio::spawn(handle_reader(server, sender, 0, room_sender, config, stats, Arc::new(AtomicUsize::new(0)), Arc::default(), shutdown));
+
+        client.write_all(b"duplex_alice\njoin lobby\n").await.unwrap();
+        loop {
+            match requests.recv().await.unwrap() {
+                Request::Connect(user) => assert_eq!(user.username, "duplex_alice"),
+                Request::Command(command, user) => {
+                    assert!(matches!(command, Command::Join(room) if room == "lobby"));
+                    assert_eq!(user.username, "duplex_alice");
+                    break;
+                }
+                _ => panic!("unexpected request"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn writer_stops_when_the_client_is_gone() {
+        let (client, server) = tokio::io::duplex(64);
+        drop(client);
+        let (sender, receiver) = mpsc::channel(16);
+        let (_signal, shutdown) = shutdown();
+        let stats = Arc::new(ConnectionStats::new());
+        let writer = tokio::spawn(handle_writer(server, 0, receiver, stats, Arc::new(AtomicUsize::new(0)), Arc::new(Config::default()), shutdown));
+
+        // The sender is still around, so only the failed write can stop it
+        sender.send(Outgoing::from(&b"anyone there?\n"[..])).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), writer).await.expect("writer still running").unwrap();
+    }
+
+    // Keeps every write apart, to see what went out in one syscall
+    #[derive(Default)]
+    struct Writes(Vec<Vec<u8>>);
+
+    impl AsyncWrite for Writes {
+        fn poll_write(mut self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>, buf: &[u8]) -> std::task::Poll<std::io::Result<usize>> {
+            self.0.push(buf.to_vec());
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn banner_and_prompt_are_one_write() {
+        let mut writes = Writes::default();
+        let (sender, receiver) = mpsc::channel(16);
+        let (_signal, shutdown) = shutdown();
+        send_prompt(&sender).await;
+        drop(sender);
+        handle_writer(&mut writes, 0, receiver, Arc::new(ConnectionStats::new()), Arc::new(AtomicUsize::new(0)), Arc::new(Config::default()), shutdown).await;
+
+        let [first] = &writes.0[..] else { panic!("expected one write, got {:?}", writes.0) };
+        let first = std::str::from_utf8(first).unwrap();
+        assert!(first.starts_with(BANNER));
+        assert!(first.ends_with("enter username\n"));
+    }
+
+    #[tokio::test]
+    async fn busy_rooms_task_drops_commands() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (sender, mut replies) = mpsc::channel(16);
+        // Never read, so it is full once the user has connected
+        let (room_sender, _requests) = mpsc::channel(1);
+        let config = Arc::new(Config::default());
+        let stats = Arc::new(ConnectionStats::new());
+        let (_signal, shutdown) = shutdown();
+        tokio::spawn(handle_reader(server, sender, 0, room_sender, config, stats, Arc::new(AtomicUsize::new(0)), Arc::default(), shutdown));
+
+        client.write_all(b"busy_alice\njoin lobby\n").await.unwrap();
+        loop {
+            let reply = replies.recv().await.unwrap();
+            if &reply.bytes[..] == b"* server busy, message dropped\n" {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reader_stops_at_shutdown() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (sender, _replies) = mpsc::channel(16);
+        let (room_sender, mut requests) = mpsc::channel(16);
+        let config = Arc::new(Config::default());
+        let stats = Arc::new(ConnectionStats::new());
+        let (signal, shutdown) = shutdown();
+        tokio::spawn(handle_reader(server, sender, 0, room_sender, config, stats, Arc::new(AtomicUsize::new(0)), Arc::default(), shutdown));
+
+        client.write_all(b"stopping_alice\n").await.unwrap();
+        assert!(matches!(requests.recv().await, Some(Request::Connect(_))));
+        signal.send(true).unwrap();
+        client.write_all(b"join lobby\n").await.unwrap();
+        // Only the disconnect, the join comes too late
+        assert!(matches!(requests.recv().await, Some(Request::Disconnect(..))));
+        assert!(requests.recv().await.is_none());
+    }
+
+    // Keeps every record, so tests can check what was logged at which level
+    struct CapturingLogger(Mutex<Vec<(log::Level, String)>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            self.0.lock().unwrap().push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+
+    // Everything logged so far, by every test
+    fn logged() -> Vec<(log::Level, String)> {
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+        LOGGER.0.lock().unwrap().clone()
+    }
+
+    struct BrokenSocket;
+
+    impl AsyncRead for BrokenSocket {
+        fn poll_read(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>, _buf: &mut tokio::io::ReadBuf<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Err(std::io::Error::other("broken socket")))
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_are_logged() {
+        logged();
+        let (_client, server) = tokio::io::duplex(64);
+        let (room_sender, _requests) = mpsc::channel(16);
+        let (_signal, shutdown) = shutdown();
+        handle_connection(BrokenSocket, server, room_sender, Arc::new(Config::default()), Arc::default(), shutdown).await;
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let id = loop {
+            // Other tests log too, this connection is the one with the broken socket
+            let failed = logged().into_iter().find(|(_, line)| line.ends_with("failed to read from socket: broken socket"));
+            if let Some((level, line)) = failed {
+                assert_eq!(level, log::Level::Warn);
+                break line.split(' ').nth(1).unwrap().to_string();
+            }
+            assert!(Instant::now() < deadline, "read error not logged");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        while !logged().contains(&(log::Level::Info, format!("conn {id} disconnected"))) {
+            assert!(Instant::now() < deadline, "disconnect not logged");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(logged().contains(&(log::Level::Info, format!("conn {id} connected"))));
+    }
+
+    #[tokio::test]
+    async fn accept_errors_are_logged_and_retried() {
+        logged();
+        let mut attempts = 0;
+        let accepted = accept_retrying(|| {
+            attempts += 1;
+            let result = match attempts {
+                1 | 2 => Err(std::io::Error::other("out of file descriptors for a test")),
+                _ => Ok(attempts),
+            };
+            std::future::ready(result)
+        })
+        .await;
+        assert_eq!(accepted, 3);
+        let failures = logged()
+            .into_iter()
+            .filter(|(level, line)| {
+                *level == log::Level::Warn
+                    && line == "failed to accept connection: out of file descriptors for a test"
+            })
+            .count();
+        assert_eq!(failures, 2);
+    }
+}
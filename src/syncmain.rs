@@ -1,10 +1,13 @@
 use std::collections::HashMap;
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver};
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+
+use chattery::{Frame, FrameResult, Framing, LengthFrame};
 
 type Room = String;
 type RoomSender = mpsc::Sender<(Command, Sender)>;
@@ -40,43 +43,6 @@ impl PartialEq for Sender {
 // Scenario 3: bytes\nbytes\nbyt
 // Scenario 3: bytes\nbyt
 
-struct Frame {
-    buf: Vec<u8>,
-    index: usize,
-}
-
-impl Frame {
-    pub fn new() -> Self {
-        Self {
-            buf: vec![0; 1024],
-            index: 0,
-        }
-    }
-
-    pub fn update(&mut self, bytes_read: usize) {
-        self.index += bytes_read;
-    }
-
-    fn frame(&mut self) -> Option<Vec<u8>> {
-        // Find the position of the newline char if there is one...
-        // otherwise we return None
-        let pos = self.buf[..self.index].iter().position(|b| *b == b'\n')?;
-
-        // Split the buffer at the position of the newline char.
-        // This will set self.buffer to be the value we want to return,
-        // and `let mut buf` will contain what we want to keep as self.buf,
-        // there fore...
-        let mut buf = self.buf.split_off(pos + 1);
-        // ... we need to swap self.buf with buf
-        std::mem::swap(&mut buf, &mut self.buf);
-
-        // Resize our inner buffer to fit another kb
-        self.buf.resize(1024, 0);
-
-        Some(buf)
-    }
-}
-
 // -----------------------------------------------------------------------------
 //   - Commands -
 // -----------------------------------------------------------------------------
@@ -116,7 +82,7 @@ impl Command {
                 let room = rest;
                 Some(Self::Msg { room, msg })
             }
-            _ => return None,
+            _ => None,
         }
     }
 }
@@ -152,19 +118,24 @@ fn rooms(receiver: RoomReceiver) {
                 let Some(room) = rooms.get(&room) else { continue };
                 let bytes = msg.into_bytes();
                 let bytes: Arc<[u8]> = bytes.into();
+                // Recipients who disconnected can't be sent to, which is fine to ignore
                 room.iter().filter(|s| *s != &sender).for_each(|recipient| {
-                    recipient.inner.send(bytes.clone());
+                    let _ = recipient.inner.send(bytes.clone());
                 });
             }
         }
     }
 }
 
-fn handle_reader(mut reader: TcpStream, sender: Sender, room_sender: mpsc::Sender<(Command, Sender)>) {
-    let mut frame = Frame::new();
+fn handle_reader(
+    mut reader: TcpStream,
+    sender: Sender,
+    room_sender: mpsc::Sender<(Command, Sender)>,
+    mut frame: Box<dyn Framing>,
+) {
     'reader: loop {
         // Step 1: read into the `frame`
-        match reader.read(&mut frame.buf) {
+        match reader.read(frame.unfilled()) {
             // Read zero bytes means the socket hung up on the other end.
             // This could be that the user just closed the connection, or
             // killed the program, or just turned off their computer?!?!
@@ -183,24 +154,35 @@ fn handle_reader(mut reader: TcpStream, sender: Sender, room_sender: mpsc::Sende
         };
 
         // Step 2: get messages out of the frame (frame messages)
-        while let Some(payload) = frame.frame() {
+        loop {
+            let payload = match frame.frame() {
+                FrameResult::Complete(payload) => payload,
+                // Dropped, like any other line that doesn't parse
+                FrameResult::TooLong => continue,
+                FrameResult::Incomplete => break,
+            };
             // Step 3: parse message
             let Some(command) = Command::parse(payload) else { continue };
 
-            // Step 4: send message to rooms
-            room_sender.send((command, sender.clone()));
+            // Step 4: send message to rooms, unless they are gone
+            if room_sender.send((command, sender.clone())).is_err() {
+                break 'reader;
+            }
         }
     }
 }
 
+// Stops once the client is gone
 fn handle_writer(mut writer: TcpStream, receiver: Receiver<Arc<[u8]>>) {
     while let Ok(message) = receiver.recv() {
-        writer.write_all(&message);
-        writer.flush();
+        if let Err(e) = writer.write_all(&message).and_then(|_| writer.flush()) {
+            eprintln!("Failed to write to socket: {e}");
+            break;
+        }
     }
 }
 
-fn handle_connection(reader: TcpStream, room_sender: RoomSender) {
+fn handle_connection(reader: TcpStream, room_sender: RoomSender, length_framing: bool) {
     static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
     let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
 
@@ -209,18 +191,81 @@ fn handle_connection(reader: TcpStream, room_sender: RoomSender) {
 
     let writer = reader.try_clone().unwrap();
     thread::spawn(move || handle_writer(writer, receiver));
-    thread::spawn(move || handle_reader(reader, sender, room_sender));
+    let frame: Box<dyn Framing> = if length_framing { Box::new(LengthFrame::new()) } else { Box::new(Frame::new()) };
+    thread::spawn(move || handle_reader(reader, sender, room_sender, frame));
+}
+
+// Accepts until it works. Errors are logged and waited out, longer the more
+// there are in a row, e.g. being out of file descriptors passes once some
+// connections close.
+fn accept_retrying<T>(mut accept: impl FnMut() -> std::io::Result<T>, mut log: impl Write) -> T {
+    let mut failures = 0;
+    loop {
+        match accept() {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                let _ = writeln!(log, "Failed to accept connection: {e}");
+                failures += 1;
+                thread::sleep(Duration::from_millis(100) * failures.min(10));
+            }
+        }
+    }
+}
+
+// `--bind` takes an ip address and a port, e.g. 0.0.0.0:9000
+fn parse_bind(addr: &str) -> Result<SocketAddr, String> {
+    addr.parse().map_err(|_| format!("invalid bind address '{addr}', expected <ip>:<port>"))
 }
 
 fn main() {
-    let mut listener = TcpListener::bind("127.0.0.1:5555").unwrap();
+    let args: Vec<String> = std::env::args().collect();
+    let bind = match args.iter().position(|arg| arg == "--bind") {
+        Some(pos) => {
+            let addr = args.get(pos + 1).map(String::as_str).unwrap_or_default();
+            parse_bind(addr).unwrap_or_else(|e| {
+                eprintln!("{e}");
+                std::process::exit(1);
+            })
+        }
+        None => SocketAddr::from(([127, 0, 0, 1], 5555)),
+    };
+    // Length prefixed messages instead of lines, see `LengthFrame`
+    let length_framing = args.iter().any(|arg| arg == "--length-framing");
+
+    let listener = TcpListener::bind(bind).unwrap();
 
     // Setup rooms here
     let (room_sender, room_receiver) = mpsc::channel();
     thread::spawn(move || rooms(room_receiver));
 
     loop {
-        let (stream, _addr) = listener.accept().unwrap();
-        handle_connection(stream, room_sender.clone());
+        let (stream, _addr) = accept_retrying(|| listener.accept(), std::io::stderr());
+        handle_connection(stream, room_sender.clone(), length_framing);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_errors_are_logged_and_retried() {
+        let mut log = vec![];
+        let mut attempts = 0;
+        let accepted = accept_retrying(
+            || {
+                attempts += 1;
+                match attempts {
+                    1 | 2 => Err(std::io::Error::other("out of file descriptors")),
+                    _ => Ok(attempts),
+                }
+            },
+            &mut log,
+        );
+        assert_eq!(accepted, 3);
+        assert_eq!(
+            String::from_utf8(log).unwrap(),
+            "Failed to accept connection: out of file descriptors\n".repeat(2)
+        );
     }
 }
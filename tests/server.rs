@@ -0,0 +1,2230 @@
+// End to end tests against a real server, talking to it over sockets the
+// same way a client would.
+//
+// Every test runs its own server on a free port, with usernames and
+// counters of its own.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chattery::{AuthFuture, AuthResult, Authenticator, Config, Server, ShutdownHandle, TlsFiles};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, Lines};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+// How long to wait for a line before failing the test
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a client has to stay quiet to count as not having been sent anything
+const SILENCE: Duration = Duration::from_millis(300);
+
+async fn start_server() -> SocketAddr {
+    start_server_with(Config::default()).await.0
+}
+
+async fn start_server_with(config: Config) -> (SocketAddr, ShutdownHandle) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = Server::from_listener(config, listener).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(server.run());
+    (addr, shutdown)
+}
+
+struct Client<R, W> {
+    lines: Lines<BufReader<R>>,
+    writer: W,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Client<R, W> {
+    // Picks a username and waits for the server to accept it
+    async fn login(reader: R, writer: W, username: &str) -> Self {
+        let mut client = Self { lines: BufReader::new(reader).lines(), writer };
+        client.send(username).await;
+        client.read_until(|line| line.starts_with("* session ")).await;
+        client
+    }
+
+    // Switches to `mode raw`, without picking a username
+    async fn raw(reader: R, writer: W) -> Self {
+        let mut client = Self { lines: BufReader::new(reader).lines(), writer };
+        client.send("mode raw").await;
+        // Without the banner and prompt
+        assert_eq!(client.read_line().await, "OK mode raw");
+        client
+    }
+
+    // Switches to `mode raw` before picking a username
+    async fn login_raw(reader: R, writer: W, username: &str) -> Self {
+        let mut client = Self::raw(reader, writer).await;
+        client.send(username).await;
+        client.read_until(|line| line.starts_with("OK login ")).await;
+        client
+    }
+
+    async fn send(&mut self, line: &str) {
+        self.writer.write_all(format!("{line}\n").as_bytes()).await.unwrap();
+    }
+
+    // The next line, without the newline
+    async fn read_line(&mut self) -> String {
+        match tokio::time::timeout(READ_TIMEOUT, self.lines.next_line()).await {
+            Ok(Ok(Some(line))) => line,
+            Ok(Ok(None)) => panic!("connection closed"),
+            Ok(Err(e)) => panic!("failed to read: {e}"),
+            Err(_) => panic!("no line within {READ_TIMEOUT:?}"),
+        }
+    }
+
+    // Skips lines up to and including the first that matches
+    async fn read_until(&mut self, matches: impl Fn(&str) -> bool) -> String {
+        loop {
+            let line = self.read_line().await;
+            if matches(&line) {
+                return line;
+            }
+        }
+    }
+
+    // Reads to the end, then checks that the server closed its side as well:
+    // writing to a socket nobody reads from any more is answered with a reset
+    async fn assert_closed(&mut self) {
+        loop {
+            match tokio::time::timeout(READ_TIMEOUT, self.lines.next_line()).await {
+                Ok(Ok(Some(_))) => {}
+                Ok(Ok(None)) | Ok(Err(_)) => break,
+                Err(_) => panic!("connection still open after {READ_TIMEOUT:?}"),
+            }
+        }
+        for _ in 0..50 {
+            if self.writer.write_all(b"ping\n").await.is_err() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("the server still reads from the connection");
+    }
+
+    async fn assert_silent(&mut self) {
+        if let Ok(line) = tokio::time::timeout(SILENCE, self.lines.next_line()).await {
+            panic!("expected nothing, got {line:?}");
+        }
+    }
+}
+
+async fn connect(addr: SocketAddr, username: &str) -> Client<impl AsyncRead + Unpin, impl AsyncWrite + Unpin> {
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    Client::login(reader, writer, username).await
+}
+
+#[tokio::test]
+async fn message_reaches_other_member() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "msg_alice").await;
+    let mut bob = connect(addr, "msg_bob").await;
+
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* msg_bob joined lobby").await;
+
+    alice.send("msg lobby hello bob").await;
+    assert_eq!(bob.read_until(|line| !line.starts_with('*')).await, "msg_alice: hello bob");
+}
+
+#[tokio::test]
+async fn part_leaves_room() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "part_alice").await;
+    let mut bob = connect(addr, "part_bob").await;
+
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* part_bob joined lobby").await;
+
+    bob.send("part lobby").await;
+    alice.read_until(|line| line == "* part_bob left lobby").await;
+    // Whatever bob was sent about leaving
+    while tokio::time::timeout(SILENCE, bob.lines.next_line()).await.is_ok() {}
+
+    alice.send("msg lobby anyone here").await;
+    bob.assert_silent().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket() {
+    let path = std::env::temp_dir().join(format!("chattery-test-{}.sock", std::process::id()));
+    let mut server = std::process::Command::new(env!("CARGO_BIN_EXE_chattery"))
+        .args(["--unix".as_ref(), path.as_os_str(), "--no-tcp".as_ref(), "--no-health".as_ref()])
+        .env("RUST_LOG", "off")
+        .spawn()
+        .unwrap();
+
+    // Wait for the server to start listening
+    let mut stream = None;
+    for _ in 0..50 {
+        if let Ok(connected) = UnixStream::connect(&path).await {
+            stream = Some(connected);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let (reader, writer) = stream.expect("server never started listening").into_split();
+    let mut alice = Client::login(reader, writer, "unix_alice").await;
+    let (reader, writer) = UnixStream::connect(&path).await.unwrap().into_split();
+    let mut bob = Client::login(reader, writer, "unix_bob").await;
+
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* unix_bob joined lobby").await;
+    alice.send("msg lobby over a socket").await;
+    let line = bob.read_until(|line| !line.starts_with('*')).await;
+
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(line, "unix_alice: over a socket");
+}
+
+// `human_mode` and `raw_mode` send the same commands.
+// Each is answered before the next is sent, as refusals from the reader
+// (e.g. unknown commands) could otherwise overtake replies from the rooms task.
+#[tokio::test]
+async fn human_mode() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "human_alice").await;
+
+    // Nothing is sent back for joining
+    alice.send("join lobby").await;
+    alice.send("msg nowhere hello").await;
+    assert_eq!(alice.read_line().await, "* no such room nowhere [nosuchroom]");
+    alice.send("who lobby").await;
+    assert_eq!(alice.read_line().await, "* users in lobby: human_alice");
+    alice.send("frobnicate").await;
+    assert_eq!(alice.read_line().await, "error: unknown command 'frobnicate'");
+}
+
+#[tokio::test]
+async fn raw_mode() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "raw_alice").await;
+
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+    alice.send("msg nowhere hello").await;
+    assert_eq!(alice.read_line().await, "ERR nosuchroom");
+    alice.send("who lobby").await;
+    assert_eq!(alice.read_line().await, "* users in lobby: raw_alice");
+    assert_eq!(alice.read_line().await, "OK who");
+    alice.send("frobnicate").await;
+    assert_eq!(alice.read_line().await, "ERR unknowncommand");
+}
+
+#[tokio::test]
+async fn shutdown_handle_stops_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = Server::from_listener(Config::default(), listener).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let running = tokio::spawn(server.run());
+    let mut alice = connect(addr, "shutdown_alice").await;
+
+    shutdown.shutdown();
+    let stopped = tokio::time::timeout(READ_TIMEOUT, running).await.expect("server still running");
+    stopped.unwrap().unwrap();
+    // Told why before the connection is closed
+    assert_eq!(alice.read_line().await, "* server shutting down");
+    alice.assert_closed().await;
+}
+
+#[tokio::test]
+async fn shutdown_closes_every_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = Server::from_listener(Config::default(), listener).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let running = tokio::spawn(server.run());
+    let mut alice = connect(addr, "closed_alice").await;
+    alice.send("join lobby").await;
+    alice.send("who lobby").await;
+    alice.read_until(|line| line.starts_with("* users in lobby")).await;
+    // Still to pick a username
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut anon = Client { lines: BufReader::new(reader).lines(), writer };
+    anon.read_until(|line| line == "enter username").await;
+
+    shutdown.shutdown();
+    tokio::time::timeout(READ_TIMEOUT, running).await.expect("server still running").unwrap().unwrap();
+    alice.assert_closed().await;
+    anon.assert_closed().await;
+}
+
+// A self-signed certificate for localhost, written to files for `TlsFiles`,
+// and a connector that trusts it
+fn self_signed(name: &str) -> (TlsFiles, TlsConnector) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let dir = std::env::temp_dir();
+    let files = TlsFiles {
+        cert: dir.join(format!("chattery-test-{}-{name}-cert.pem", std::process::id())),
+        key: dir.join(format!("chattery-test-{}-{name}-key.pem", std::process::id())),
+    };
+    std::fs::write(&files.cert, certified.cert.pem()).unwrap();
+    std::fs::write(&files.key, certified.key_pair.serialize_pem()).unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    (files, TlsConnector::from(Arc::new(config)))
+}
+
+async fn connect_tls(addr: SocketAddr, connector: &TlsConnector, username: &str) -> Client<impl AsyncRead + Unpin, impl AsyncWrite + Unpin> {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let stream = connector.connect(ServerName::try_from("localhost").unwrap(), stream).await.unwrap();
+    let (reader, writer) = tokio::io::split(stream);
+    Client::login(reader, writer, username).await
+}
+
+#[tokio::test]
+async fn tls_connections_chat() {
+    let (files, connector) = self_signed("chat");
+    let (addr, _shutdown) = start_server_with(Config { tls: Some(files.clone()), ..Config::default() }).await;
+    let mut alice = connect_tls(addr, &connector, "tls_alice").await;
+    let mut bob = connect_tls(addr, &connector, "tls_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* tls_bob joined lobby").await;
+
+    alice.send("msg lobby over tls").await;
+    assert_eq!(bob.read_until(|line| !line.starts_with('*')).await, "tls_alice: over tls");
+    let _ = std::fs::remove_file(&files.cert);
+    let _ = std::fs::remove_file(&files.key);
+}
+
+#[tokio::test]
+async fn bind_fails_without_tls_files() {
+    let missing = std::env::temp_dir().join("chattery-test-missing-cert.pem");
+    let files = TlsFiles { cert: missing.clone(), key: missing.clone() };
+    let config = Config { bind: "127.0.0.1:0".parse().unwrap(), tls: Some(files), ..Config::default() };
+    match Server::bind(config).await {
+        Ok(_) => panic!("bound without a certificate"),
+        Err(e) => assert!(e.to_string().contains(&missing.display().to_string()), "{e}"),
+    }
+}
+
+#[tokio::test]
+async fn health_check() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = Config { health: Some("127.0.0.1:0".parse().unwrap()), max_connections: 10, ..Config::default() };
+    let server = Server::from_listener(config, listener).await.unwrap();
+    let (addr, health) = (server.local_addr().unwrap(), server.health_addr().unwrap());
+    tokio::spawn(server.run());
+    let _alice = connect(addr, "health_alice").await;
+
+    // One line and then the connection is closed, without any handshake
+    let mut stream = TcpStream::connect(health).await.unwrap();
+    let mut status = String::new();
+    tokio::time::timeout(Duration::from_secs(1), stream.read_to_string(&mut status))
+        .await
+        .expect("health check too slow")
+        .unwrap();
+    // Only counts this server's connections
+    assert_eq!(status, "OK 1/10\n");
+}
+
+#[tokio::test]
+async fn full_server_turns_connections_away() {
+    let config = Config { max_connections: 1, ..Config::default() };
+    let (addr, _shutdown) = start_server_with(config).await;
+    let _alice = connect(addr, "full_alice").await;
+
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut bob = Client { lines: BufReader::new(reader).lines(), writer };
+    assert_eq!(bob.read_line().await, "* server full");
+    bob.assert_closed().await;
+}
+
+// Passwords by username
+struct Passwords(HashMap<&'static str, &'static str>);
+
+impl Authenticator for Passwords {
+    fn verify<'a>(&'a self, user: &'a str, token: &'a str) -> AuthFuture<'a> {
+        let result = match self.0.get(user) {
+            Some(password) if *password == token => AuthResult::Accepted,
+            _ => AuthResult::Rejected,
+        };
+        Box::pin(std::future::ready(result))
+    }
+}
+
+async fn start_server_with_passwords(passwords: &[(&'static str, &'static str)]) -> SocketAddr {
+    let passwords = Passwords(passwords.iter().copied().collect());
+    let config = Config { authenticator: Some(Arc::new(passwords)), ..Config::default() };
+    start_server_with(config).await.0
+}
+
+#[tokio::test]
+async fn custom_authenticator() {
+    let addr = start_server_with_passwords(&[("auth_alice", "hunter2")]).await;
+
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::raw(reader, writer).await;
+    alice.send("auth_alice").await;
+    assert_eq!(alice.read_line().await, "OK username");
+    alice.send("auth wrong").await;
+    assert_eq!(alice.read_line().await, "ERR invalidtoken");
+    alice.send("auth hunter2").await;
+    alice.read_until(|line| line.starts_with("OK login ")).await;
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+
+    // The right password for someone else's name
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut bob = Client::raw(reader, writer).await;
+    bob.send("auth_bob").await;
+    assert_eq!(bob.read_line().await, "OK username");
+    bob.send("auth hunter2").await;
+    assert_eq!(bob.read_line().await, "ERR invalidtoken");
+    bob.send("join lobby").await;
+    assert_eq!(bob.read_line().await, "ERR unauthenticated");
+}
+
+#[tokio::test]
+async fn username_claimed_after_auth() {
+    let addr = start_server_with_passwords(&[("squat_alice", "hunter2")]).await;
+
+    // Picks the name, but never authenticates
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut squatter = Client::raw(reader, writer).await;
+    squatter.send("squat_alice").await;
+    assert_eq!(squatter.read_line().await, "OK username");
+
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::raw(reader, writer).await;
+    alice.send("squat_alice").await;
+    alice.send("auth hunter2").await;
+    alice.read_until(|line| line.starts_with("OK login ")).await;
+
+    // Too late now, even with the right password
+    squatter.send("auth hunter2").await;
+    assert_eq!(squatter.read_line().await, "ERR taken");
+}
+
+#[tokio::test]
+async fn wrong_tokens_disconnect() {
+    let addr = start_server_with_passwords(&[("guess_alice", "hunter2")]).await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut mallory = Client::raw(reader, writer).await;
+    mallory.send("guess_alice").await;
+    assert_eq!(mallory.read_line().await, "OK username");
+
+    mallory.send("auth password").await;
+    assert_eq!(mallory.read_line().await, "ERR invalidtoken");
+    mallory.send("auth 123456").await;
+    assert_eq!(mallory.read_line().await, "ERR invalidtoken");
+    mallory.send("auth letmein").await;
+    assert_eq!(mallory.read_line().await, "ERR invalidtoken");
+    let closed = tokio::time::timeout(READ_TIMEOUT, mallory.lines.next_line()).await;
+    assert!(matches!(closed, Ok(Ok(None))), "{closed:?}");
+}
+
+#[tokio::test]
+async fn unauthenticated_rate_limited() {
+    let addr = start_server_with_passwords(&[("flood_alice", "hunter2")]).await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::raw(reader, writer).await;
+    alice.send("flood_alice").await;
+
+    for _ in 0..20 {
+        alice.send("join lobby").await;
+    }
+    alice.read_until(|line| line == "ERR ratelimited").await;
+}
+
+#[tokio::test]
+async fn slow_reader_doesnt_stall_room() {
+    let config = Config { command_rate: 100_000, ..Config::default() };
+    let (addr, _shutdown) = start_server_with(config).await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "lag_alice").await;
+    let mut bob = connect(addr, "lag_bob").await;
+    let mut slow = connect(addr, "lag_slow").await;
+    for client in [&mut bob, &mut slow] {
+        client.send("join lobby").await;
+    }
+    alice.send("join lobby").await;
+    alice.read_until(|line| line == "OK join").await;
+    bob.read_until(|line| line == "* lag_alice joined lobby").await;
+    let bob = tokio::spawn(async move {
+        bob.read_until(|line| line == "lag_alice: last").await;
+    });
+
+    // Far more than the socket buffers hold, `slow` reads none of it.
+    // Sent in batches that fit in the queues, so none of it is refused.
+    let text = "x".repeat(900);
+    for _ in 0..500 {
+        for _ in 0..20 {
+            alice.send(&format!("msg lobby {text}")).await;
+        }
+        for _ in 0..20 {
+            alice.read_until(|line| line.starts_with("OK ") || line.starts_with("ERR ")).await;
+        }
+    }
+    alice.send("msg lobby last").await;
+    tokio::time::timeout(READ_TIMEOUT, bob).await.expect("bob was held up").unwrap();
+
+    // Once `slow` catches up it is told what happened
+    let warned = tokio::spawn(async move {
+        slow.read_until(|line| line == "* you are lagging, messages dropped").await;
+    });
+    while !warned.is_finished() {
+        alice.send("msg lobby still there?").await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    warned.await.unwrap();
+}
+
+#[tokio::test]
+async fn scheduled_message() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "sched_alice").await;
+    let mut bob = connect(addr, "sched_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* sched_bob joined lobby").await;
+
+    alice.send("schedule lobby 1 later").await;
+    assert_eq!(alice.read_line().await, "* scheduled message 1 in 1s");
+    bob.assert_silent().await;
+    assert_eq!(bob.read_line().await, "sched_alice: later");
+
+    // Cancelled before it is due, so never sent
+    alice.send("schedule lobby 1 never").await;
+    assert_eq!(alice.read_line().await, "* scheduled message 2 in 1s");
+    alice.send("cancelschedule 2").await;
+    assert_eq!(alice.read_line().await, "* cancelled scheduled message 2");
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    bob.assert_silent().await;
+
+    alice.send(&format!("schedule lobby {} overflow", u64::MAX)).await;
+    assert_eq!(alice.read_line().await, "error: invalid argument");
+}
+
+#[tokio::test]
+async fn scheduled_message_follows_nick() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "renamed_alice").await;
+    let mut bob = connect(addr, "renamed_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* renamed_bob joined lobby").await;
+
+    alice.send("schedule lobby 1 later").await;
+    assert_eq!(alice.read_line().await, "* scheduled message 1 in 1s");
+    alice.send("nick renamed_carol").await;
+    assert_eq!(bob.read_line().await, "* renamed_alice is now known as renamed_carol");
+    assert_eq!(bob.read_line().await, "renamed_carol: later");
+    // Counted like any other message
+    bob.send("stats").await;
+    assert_eq!(bob.read_line().await, "* server: 2 connections, 2 active, 1 messages routed, 1 rooms created, 1 open");
+}
+
+#[tokio::test]
+async fn switch_to_joined_room() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "switch_alice").await;
+    for line in ["join a", "join b"] {
+        alice.send(line).await;
+        assert_eq!(alice.read_line().await, "OK join");
+    }
+    // The room's own notice and the reply come from different tasks, in either order
+    alice.send("topic a kept").await;
+    let mut replies = [alice.read_line().await, alice.read_line().await];
+    replies.sort();
+    assert_eq!(replies, ["* topic for a set to: kept", "OK topic"]);
+
+    // Both refused without leaving anything
+    alice.send("switch a b").await;
+    assert_eq!(alice.read_line().await, "ERR alreadyjoined");
+    alice.send("switch a a").await;
+    assert_eq!(alice.read_line().await, "ERR alreadyjoined");
+    alice.send("who a").await;
+    assert_eq!(alice.read_line().await, "* users in a: switch_alice");
+    assert_eq!(alice.read_line().await, "OK who");
+    alice.send("topic a").await;
+    assert_eq!(alice.read_line().await, "* topic for a: kept");
+    assert_eq!(alice.read_line().await, "OK topic");
+
+    alice.send("switch a c").await;
+    assert_eq!(alice.read_line().await, "OK switch");
+    alice.send("who c").await;
+    assert_eq!(alice.read_line().await, "* users in c: switch_alice");
+}
+
+#[tokio::test]
+async fn topic_before_motd_and_greeting() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "order_alice").await;
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+    for line in ["setgreeting lobby * welcome", "setmotd lobby be nice", "topic lobby rust"] {
+        alice.send(line).await;
+        alice.read_until(|line| line.starts_with("OK ")).await;
+    }
+
+    let mut bob = connect(addr, "order_bob").await;
+    bob.send("join lobby").await;
+    assert_eq!(bob.read_line().await, "* topic for lobby: rust");
+    assert_eq!(bob.read_line().await, "* motd: be nice");
+    assert_eq!(bob.read_line().await, "* welcome");
+}
+
+#[tokio::test]
+async fn muted_users_messages_are_filtered() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "filter_alice").await;
+    let mut bob = connect(addr, "filter_bob").await;
+    let mut carol = connect(addr, "filter_carol").await;
+    for client in [&mut alice, &mut bob, &mut carol] {
+        client.send("join lobby").await;
+    }
+    alice.read_until(|line| line == "* filter_carol joined lobby").await;
+    alice.send("mute filter_bob").await;
+    assert_eq!(alice.read_line().await, "* muted filter_bob");
+
+    bob.send("msg lobby from bob").await;
+    // Carol only speaks once bob's message went out, so alice would have it first
+    carol.read_until(|line| line == "filter_bob: from bob").await;
+    carol.send("msg lobby from carol").await;
+    assert_eq!(alice.read_line().await, "filter_carol: from carol");
+}
+
+#[tokio::test]
+async fn mute_list_is_capped() {
+    let (addr, _shutdown) = start_server_with(Config { max_mutes: 1, ..Config::default() }).await;
+    let mut alice = connect(addr, "mute_alice").await;
+    let bob = connect(addr, "mute_bob").await;
+    let _carol = connect(addr, "mute_carol").await;
+
+    alice.send("mute mute_bob").await;
+    assert_eq!(alice.read_line().await, "* muted mute_bob");
+    alice.send("mute mute_carol").await;
+    assert_eq!(alice.read_line().await, "* ignore list full [mutesfull]");
+
+    // Unmuting frees up the mute
+    alice.send("unmute mute_bob").await;
+    assert_eq!(alice.read_line().await, "* unmuted mute_bob");
+    alice.send("mute mute_carol").await;
+    assert_eq!(alice.read_line().await, "* muted mute_carol");
+    alice.send("unmute mute_carol").await;
+    assert_eq!(alice.read_line().await, "* unmuted mute_carol");
+    alice.send("mute mute_bob").await;
+    assert_eq!(alice.read_line().await, "* muted mute_bob");
+
+    // So does leaving
+    drop(bob);
+    tokio::time::sleep(SILENCE).await;
+    alice.send("mute mute_carol").await;
+    assert_eq!(alice.read_line().await, "* muted mute_carol");
+}
+
+// Every command is answered with `OK` or `ERR`, even those that do nothing
+#[tokio::test]
+async fn raw_mode_always_answers() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "always_alice").await;
+
+    for (command, reply) in [
+        ("unwatch nowhere", "ERR nosuchroom"),
+        ("part nowhere", "ERR notmember"),
+        ("join lobby", "OK join"),
+        ("unlink lobby", "ERR notadmin"),
+        ("typing nowhere", "ERR notmember"),
+        ("typing lobby", "ERR ratelimited"),
+    ] {
+        alice.send(command).await;
+        assert_eq!(alice.read_line().await, reply, "{command}");
+    }
+}
+
+// Logs in and returns the session token
+async fn session_token(client: &mut Client<impl AsyncRead + Unpin, impl AsyncWrite + Unpin>, username: &str) -> String {
+    client.send(username).await;
+    let line = client.read_until(|line| line.starts_with("* session ")).await;
+    line["* session ".len()..].to_string()
+}
+
+#[tokio::test]
+async fn resume_session() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client { lines: BufReader::new(reader).lines(), writer };
+    let token = session_token(&mut alice, "resume_alice").await;
+    assert_eq!(token.len(), 32);
+    alice.send("join lobby").await;
+    alice.send("who lobby").await;
+    alice.read_until(|line| line == "* users in lobby: resume_alice").await;
+    drop(alice);
+    tokio::time::sleep(SILENCE).await;
+
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client { lines: BufReader::new(reader).lines(), writer };
+    alice.send(&format!("resume {token}")).await;
+    alice.read_until(|line| line == "* resumed as resume_alice").await;
+    alice.send("who lobby").await;
+    assert_eq!(alice.read_line().await, "* users in lobby: resume_alice");
+
+    // Only once
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut mallory = Client { lines: BufReader::new(reader).lines(), writer };
+    mallory.send(&format!("resume {token}")).await;
+    mallory.read_until(|line| line == "* invalid or expired session, enter username").await;
+}
+
+#[tokio::test]
+async fn loadrooms_rejoins_the_last_rooms() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "load_alice").await;
+    alice.send("loadrooms").await;
+    assert_eq!(alice.read_line().await, "* no rooms to load");
+    alice.send("join a").await;
+    alice.send("join b").await;
+    alice.send("who b").await;
+    alice.read_until(|line| line == "* users in b: load_alice").await;
+    drop(alice);
+    tokio::time::sleep(SILENCE).await;
+
+    let mut alice = connect(addr, "load_alice").await;
+    alice.send("loadrooms").await;
+    assert_eq!(alice.read_line().await, "* loaded rooms: a, b");
+    for room in ["a", "b"] {
+        alice.send(&format!("who {room}")).await;
+        assert_eq!(alice.read_line().await, format!("* users in {room}: load_alice"));
+    }
+    // Only once
+    alice.send("loadrooms").await;
+    assert_eq!(alice.read_line().await, "* no rooms to load");
+}
+
+#[tokio::test]
+async fn resume_expired_session() {
+    let config = Config { session_ttl: Duration::from_millis(100), ..Config::default() };
+    let (addr, _shutdown) = start_server_with(config).await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client { lines: BufReader::new(reader).lines(), writer };
+    let token = session_token(&mut alice, "expired_alice").await;
+    drop(alice);
+    tokio::time::sleep(SILENCE).await;
+
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client { lines: BufReader::new(reader).lines(), writer };
+    alice.send(&format!("resume {token}")).await;
+    alice.read_until(|line| line == "* invalid or expired session, enter username").await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket_in_use() {
+    let path = std::env::temp_dir().join(format!("chattery-test-{}-in-use.sock", std::process::id()));
+    // Left behind by a server that is gone, so it is replaced
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    let config = Config { tcp: false, unix: Some(path.clone()), ..Config::default() };
+    let server = Server::bind(config).await.unwrap();
+    let shutdown = server.shutdown_handle();
+    let running = tokio::spawn(server.run());
+
+    // Still answering, so left alone
+    let config = Config { tcp: false, unix: Some(path.clone()), ..Config::default() };
+    let Err(e) = Server::bind(config).await else { panic!("bound a socket in use") };
+    assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+    let (reader, writer) = UnixStream::connect(&path).await.unwrap().into_split();
+    Client::login(reader, writer, "in_use_alice").await;
+
+    shutdown.shutdown();
+    running.await.unwrap().unwrap();
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn history_survives_restart() {
+    let path = std::env::temp_dir().join(format!("chattery-test-{}-history.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = || Config { history_file: Some(path.to_string_lossy().into_owned()), ..Config::default() };
+
+    let (addr, shutdown) = start_server_with(config()).await;
+    let mut alice = connect(addr, "history_alice").await;
+    alice.send("join lobby").await;
+    alice.send("msg lobby before the restart").await;
+    alice.send("who lobby").await;
+    alice.read_until(|line| line.starts_with("* users in lobby")).await;
+    shutdown.shutdown();
+    // The log is written in the background
+    tokio::time::sleep(SILENCE).await;
+
+    let (addr, _shutdown) = start_server_with(config()).await;
+    let mut bob = connect(addr, "history_bob").await;
+    bob.send("join lobby").await;
+    assert_eq!(bob.read_line().await, "history_alice: before the restart");
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn json_events_carry_the_room() {
+    let (addr, shutdown) = start_server_with(Config { json: true, ..Config::default() }).await;
+    let mut clients = vec![];
+    for name in ["json_alice", "json_bob"] {
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut client = Client { lines: BufReader::new(reader).lines(), writer };
+        client.send(&format!(r#"{{"cmd":"login","name":"{name}"}}"#)).await;
+        client.read_until(|line| line.contains(r#""text":"session "#)).await;
+        client.send(r#"{"cmd":"join","room":"lobby"}"#).await;
+        clients.push(client);
+    }
+    let (mut alice, mut bob) = (clients.remove(0), clients.remove(0));
+    alice.read_until(|line| line.contains("json_bob joined lobby")).await;
+
+    // Not a message to room `a`
+    alice.send(r#"{"cmd":"msg","room":"a b","text":"hi"}"#).await;
+    assert_eq!(alice.read_line().await, r#"{"type":"error","text":"room names can't contain whitespace"}"#);
+
+    alice.send(r#"{"cmd":"msg","room":"lobby","text":"hi"}"#).await;
+    let message = bob.read_until(|line| line.contains(r#""type":"msg""#)).await;
+    assert_eq!(message, r#"{"type":"msg","room":"lobby","seq":1,"from":"json_alice","text":"hi"}"#);
+
+    shutdown.shutdown();
+    let goodbye = bob.read_until(|line| line.contains("shutting down")).await;
+    assert_eq!(goodbye, r#"{"type":"notice","text":"server shutting down"}"#);
+}
+
+async fn start_admin_server() -> SocketAddr {
+    start_server_with(Config { admin_token: Some("letmein".into()), ..Config::default() }).await.0
+}
+
+#[tokio::test]
+async fn link_bridges_two_servers() {
+    let (here, there) = (start_admin_server().await, start_server().await);
+    let (reader, writer) = TcpStream::connect(here).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "link_alice").await;
+    let (reader, writer) = TcpStream::connect(there).await.unwrap().into_split();
+    let mut bob = Client::login_raw(reader, writer, "link_bob").await;
+    for room in ["lobby", "hall"] {
+        alice.send(&format!("join {room}")).await;
+        assert_eq!(alice.read_line().await, "OK join");
+        bob.send(&format!("join {room}")).await;
+        assert_eq!(bob.read_line().await, "OK join");
+    }
+
+    alice.send(&format!("link lobby {there}")).await;
+    assert_eq!(alice.read_line().await, "ERR notadmin");
+    alice.send("admin wrong").await;
+    assert_eq!(alice.read_line().await, "ERR invalidtoken");
+    alice.send("admin letmein").await;
+    assert_eq!(alice.read_line().await, "OK admin");
+
+    // Both relays log in to the same server
+    for room in ["lobby", "hall"] {
+        alice.send(&format!("link {room} {there}")).await;
+        assert_eq!(alice.read_line().await, "OK link");
+        let joined = bob.read_until(|line| line.starts_with("* relay-")).await;
+        assert!(joined.ends_with(&format!(" joined {room}")), "{joined}");
+    }
+
+    alice.send("msg hall hello over there").await;
+    assert_eq!(alice.read_line().await, "OK msg");
+    let relayed = bob.read_until(|line| line.starts_with("relay-")).await;
+    assert!(relayed.ends_with(": link_alice: hello over there"), "{relayed}");
+}
+
+#[tokio::test]
+async fn failed_link_is_reported_and_cleared() {
+    let here = start_admin_server().await;
+    let (reader, writer) = TcpStream::connect(here).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "badlink_alice").await;
+    alice.send("admin letmein").await;
+    assert_eq!(alice.read_line().await, "OK admin");
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+
+    // Nothing listening
+    let nowhere = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    // Lets nobody in without a password
+    let locked = start_server_with_passwords(&[("someone", "hunter2")]).await;
+    for (addr, reason) in [(nowhere.to_string(), "failed: "), (locked.to_string(), "failed: a token is required")] {
+        alice.send(&format!("link lobby {addr}")).await;
+        assert_eq!(alice.read_line().await, "OK link");
+        let failed = alice.read_line().await;
+        assert!(failed.starts_with(&format!("* link of lobby to {addr} {reason}")), "{failed}");
+        // The link was cleared, so the room can be linked again
+        alice.send("unlink lobby").await;
+        assert_eq!(alice.read_line().await, "ERR notlinked");
+    }
+}
+
+#[tokio::test]
+async fn config_shows_settings() {
+    let config = Config { history_len: 5, admin_token: Some("letmein".into()), ..Config::default() };
+    let addr = start_server_with(config).await.0;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "config_alice").await;
+    alice.send("config").await;
+    assert_eq!(alice.read_line().await, "ERR notadmin");
+    alice.send("admin letmein").await;
+    assert_eq!(alice.read_line().await, "OK admin");
+
+    alice.send("config").await;
+    let mut settings = vec![];
+    loop {
+        match alice.read_line().await {
+            line if line == "OK config" => break,
+            line => settings.push(line),
+        }
+    }
+    assert!(settings.contains(&"* config history_len 5".to_string()), "{settings:?}");
+    assert!(settings.contains(&"* config admin_token <redacted>".to_string()), "{settings:?}");
+    assert!(!settings.iter().any(|line| line.contains("letmein")), "{settings:?}");
+}
+
+#[tokio::test]
+async fn timestamps_use_the_clock() {
+    fn clock() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(3 * 24 * 60 * 60 + 12 * 60 * 60 + 34 * 60 + 5)
+    }
+    let (addr, _) = start_server_with(Config { timestamps: true, clock, ..Config::default() }).await;
+    let mut alice = connect(addr, "clock_alice").await;
+    let mut bob = connect(addr, "clock_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* clock_bob joined lobby").await;
+
+    alice.send("msg lobby hi").await;
+    assert_eq!(bob.read_until(|line| !line.starts_with('*')).await, "[12:34:05] clock_alice: hi");
+}
+
+#[tokio::test]
+async fn frametest_frames_in_order() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "frametest_alice").await;
+
+    alice.send("frametest").await;
+    assert_eq!(alice.read_line().await, "* frametest begin");
+    assert_eq!(alice.read_line().await, "* split line");
+    // `lines` drops the \r along with the \n
+    assert_eq!(alice.read_line().await, "* crlf line");
+    // As long as the longest line the server takes, 8 KiB with the newline
+    let long_line = alice.read_line().await;
+    assert_eq!(long_line.len(), 8 * 1024 - 1);
+    assert!(long_line.starts_with("*x") && long_line.trim_start_matches('*').bytes().all(|b| b == b'x'));
+    assert_eq!(alice.read_line().await, "* frametest end");
+    assert_eq!(alice.read_line().await, "OK frametest");
+}
+
+#[tokio::test]
+async fn since_replays_newer_messages() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "since_alice").await;
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+    for msg in ["one", "two", "three"] {
+        alice.send(&format!("msg lobby {msg}")).await;
+        assert_eq!(alice.read_line().await, "OK msg");
+    }
+
+    alice.send("since lobby 1").await;
+    assert_eq!(alice.read_line().await, "[lobby #2] since_alice: two");
+    assert_eq!(alice.read_line().await, "[lobby #3] since_alice: three");
+    assert_eq!(alice.read_line().await, "OK since");
+    alice.send("since lobby 3").await;
+    assert_eq!(alice.read_line().await, "OK since");
+    alice.send("since lobby x").await;
+    assert_eq!(alice.read_line().await, "ERR invalidargument");
+}
+
+#[tokio::test]
+async fn react_to_message() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "react_alice").await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut bob = Client::login_raw(reader, writer, "react_bob").await;
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+    bob.send("join lobby").await;
+    assert_eq!(bob.read_line().await, "OK join");
+    alice.send("msg lobby hi").await;
+    assert_eq!(bob.read_line().await, "react_alice: hi");
+
+    bob.send("react lobby 1 👍").await;
+    assert_eq!(alice.read_until(|line| line.contains("reacted")).await, "* react_bob reacted 👍 to #1");
+    bob.send("react lobby 42 👍").await;
+    assert_eq!(bob.read_until(|line| line.starts_with("ERR")).await, "ERR nosuchmessage");
+
+    let mut carol = connect(addr, "react_carol").await;
+    carol.send("join lobby").await;
+    carol.read_until(|line| line == "react_alice: hi").await;
+    carol.send("react lobby 42 👍").await;
+    assert_eq!(carol.read_line().await, "* no such message #42 [nosuchmessage]");
+}
+
+#[tokio::test]
+async fn buflimit_lets_longer_lines_through() {
+    let addr = start_admin_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "buflimit_alice").await;
+    alice.send("buflimit 1024").await;
+    assert_eq!(alice.read_line().await, "ERR notadmin");
+    alice.send("admin letmein").await;
+    assert_eq!(alice.read_line().await, "OK admin");
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+    alice.send("buflimit").await;
+    assert_eq!(alice.read_line().await, "OK buflimit 8192");
+
+    // Over 1 KiB, but short enough for a message at 700 chars
+    let long = format!("msg lobby {}", "é".repeat(700));
+    alice.send("buflimit 1024").await;
+    assert_eq!(alice.read_line().await, "OK buflimit 1024");
+    alice.send(&long).await;
+    assert_eq!(alice.read_line().await, "ERR toolong");
+    alice.send("buflimit 4096").await;
+    assert_eq!(alice.read_line().await, "OK buflimit 4096");
+    alice.send(&long).await;
+    assert_eq!(alice.read_line().await, "OK msg");
+}
+
+#[tokio::test]
+async fn export_sends_room_history() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "export_alice").await;
+    alice.send("export lobby").await;
+    assert_eq!(alice.read_line().await, "ERR nosuchroom");
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+    for msg in ["one", "two", "three"] {
+        alice.send(&format!("msg lobby {msg}")).await;
+        assert_eq!(alice.read_line().await, "OK msg");
+    }
+
+    alice.send("export lobby").await;
+    assert_eq!(alice.read_line().await, "* export of lobby: 3 messages");
+    assert_eq!(alice.read_line().await, "[lobby #1] export_alice: one");
+    assert_eq!(alice.read_line().await, "[lobby #2] export_alice: two");
+    assert_eq!(alice.read_line().await, "[lobby #3] export_alice: three");
+    assert_eq!(alice.read_line().await, "* end of export of lobby");
+    assert_eq!(alice.read_line().await, "OK export");
+
+    // Members only
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut bob = Client::login_raw(reader, writer, "export_bob").await;
+    bob.send("export lobby").await;
+    assert_eq!(bob.read_line().await, "ERR notmember");
+}
+
+#[tokio::test]
+async fn isonline_respects_invisible() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "online_alice").await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut bob = Client::login_raw(reader, writer, "online_bob").await;
+
+    alice.send("isonline online_bob").await;
+    assert_eq!(alice.read_line().await, "* online_bob is online");
+    assert_eq!(alice.read_line().await, "OK isonline");
+    alice.send("isonline online_nobody").await;
+    assert_eq!(alice.read_line().await, "* online_nobody is offline");
+    assert_eq!(alice.read_line().await, "OK isonline");
+
+    bob.send("invisible on").await;
+    assert_eq!(bob.read_line().await, "OK invisible");
+    alice.send("isonline online_bob").await;
+    assert_eq!(alice.read_line().await, "* online_bob is offline");
+    assert_eq!(alice.read_line().await, "OK isonline");
+}
+
+#[tokio::test]
+async fn recall_merges_rooms_in_order() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "recall_alice").await;
+    for room in ["lobby", "hall"] {
+        alice.send(&format!("join {room}")).await;
+        assert_eq!(alice.read_line().await, "OK join");
+    }
+    for (room, msg) in [("lobby", "one"), ("hall", "two"), ("lobby", "three"), ("hall", "four")] {
+        alice.send(&format!("msg {room} {msg}")).await;
+        assert_eq!(alice.read_line().await, "OK msg");
+    }
+
+    alice.send("recall 3").await;
+    assert_eq!(alice.read_line().await, "[hall #1] recall_alice: two");
+    assert_eq!(alice.read_line().await, "[lobby #2] recall_alice: three");
+    assert_eq!(alice.read_line().await, "[hall #2] recall_alice: four");
+    assert_eq!(alice.read_line().await, "OK recall");
+    alice.send("recall 0").await;
+    assert_eq!(alice.read_line().await, "ERR invalidargument");
+}
+
+#[tokio::test]
+async fn compressed_history_replays_intact() {
+    let addr = start_server_with(Config { history_compress: Some(32), ..Config::default() }).await.0;
+    let mut alice = connect(addr, "compress_alice").await;
+    alice.send("join lobby").await;
+    let long = "la ".repeat(300);
+    for msg in ["short", &long] {
+        alice.send(&format!("msg lobby {msg}")).await;
+    }
+    alice.send("since lobby 0").await;
+    alice.read_until(|line| line.starts_with("[lobby #2]")).await;
+
+    let mut bob = connect(addr, "compress_bob").await;
+    bob.send("join lobby").await;
+    assert_eq!(bob.read_until(|line| !line.starts_with('*')).await, "compress_alice: short");
+    assert_eq!(bob.read_line().await, format!("compress_alice: {long}"));
+}
+
+#[tokio::test]
+async fn banned_user_cant_rejoin() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "ban_alice").await;
+    let mut bob = connect(addr, "ban_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* ban_bob joined lobby").await;
+
+    alice.send("ban lobby ban_bob").await;
+    bob.read_until(|line| line == "* you are banned from lobby [banned]").await;
+    bob.send("join lobby").await;
+    assert_eq!(bob.read_line().await, "* you are banned from lobby [banned]");
+    alice.send("msg lobby gone?").await;
+    bob.assert_silent().await;
+}
+
+#[tokio::test]
+async fn ban_is_announced_and_outlives_nick() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "evade_alice").await;
+    let mut bob = connect(addr, "evade_bob").await;
+    let mut carol = connect(addr, "evade_carol").await;
+    for client in [&mut alice, &mut bob, &mut carol] {
+        client.send("join lobby").await;
+    }
+    alice.read_until(|line| line == "* evade_carol joined lobby").await;
+    carol.send("who lobby").await;
+    carol.read_until(|line| line.starts_with("* users in lobby")).await;
+
+    alice.send("ban lobby evade_bob").await;
+    assert_eq!(carol.read_line().await, "* evade_bob was banned from lobby");
+    bob.read_until(|line| line == "* you are banned from lobby [banned]").await;
+    bob.send("nick evade_dave").await;
+    assert_eq!(bob.read_line().await, "* you are now known as evade_dave");
+    bob.send("join lobby").await;
+    assert_eq!(bob.read_line().await, "* you are banned from lobby [banned]");
+}
+
+#[tokio::test]
+async fn rooms_and_bans_survive_a_restart() {
+    let path = std::env::temp_dir().join(format!("chattery-test-{}-rooms.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = || Config { rooms_file: Some(path.display().to_string()), ..Config::default() };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = Server::from_listener(config(), listener).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let running = tokio::spawn(server.run());
+    let mut alice = connect(addr, "saved_alice").await;
+    let mut bob = connect(addr, "saved_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* saved_bob joined lobby").await;
+    alice.send("topic lobby kept").await;
+    alice.read_until(|line| line == "* topic for lobby set to: kept").await;
+    alice.send("ban lobby saved_bob").await;
+    bob.read_until(|line| line.ends_with("[banned]")).await;
+    shutdown.shutdown();
+    tokio::time::timeout(READ_TIMEOUT, running).await.expect("server still running").unwrap().unwrap();
+    assert!(path.exists());
+
+    let (addr, _shutdown) = start_server_with(config()).await;
+    let mut carol = connect(addr, "saved_carol").await;
+    carol.send("join lobby").await;
+    assert_eq!(carol.read_line().await, "* topic for lobby: kept");
+    let mut bob = connect(addr, "saved_bob").await;
+    bob.send("join lobby").await;
+    assert_eq!(bob.read_line().await, "* you are banned from lobby [banned]");
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn long_line_isnt_a_disconnect() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "longline_alice").await;
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+
+    // More than fits in the read buffer at first
+    alice.send(&format!("msg lobby {}", "é".repeat(700))).await;
+    assert_eq!(alice.read_line().await, "OK msg");
+    alice.send("msg lobby still here").await;
+    assert_eq!(alice.read_line().await, "OK msg");
+}
+
+#[tokio::test]
+async fn overlong_line_is_refused_and_recovers() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "overlong_alice").await;
+
+    // 9 KiB without a newline is refused before the line ends
+    alice.writer.write_all(&[b'x'; 9 * 1024]).await.unwrap();
+    assert_eq!(alice.read_line().await, "ERR toolong");
+    // The rest of it is skipped, the next line is read as usual
+    alice.writer.write_all(&[b'x'; 1024]).await.unwrap();
+    alice.send("").await;
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+}
+
+#[tokio::test]
+async fn disconnect_leaves_every_room() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "dc_alice").await;
+    let mut bob = connect(addr, "dc_bob").await;
+    for room in ["lobby", "hall"] {
+        alice.send(&format!("join {room}")).await;
+    }
+    alice.send("who hall").await;
+    alice.read_until(|line| line.starts_with("* users in hall")).await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* dc_bob joined lobby").await;
+
+    drop(alice);
+    bob.read_until(|line| line == "* dc_alice left lobby").await;
+    // `hall` had no one else in it
+    bob.send("list").await;
+    assert_eq!(bob.read_line().await, "* rooms: lobby (1)");
+}
+
+#[tokio::test]
+async fn slow_reader_doesnt_stall_large_room() {
+    let config = Config { command_rate: 100_000, accept_rate: 1000, ..Config::default() };
+    let (addr, _shutdown) = start_server_with(config).await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "large_alice").await;
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+
+    // `slow` takes as little as it can off the socket and then reads none of it
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    let (reader, writer) = socket.connect(addr).await.unwrap().into_split();
+    let mut slow = Client::login(reader, writer, "large_slow").await;
+    slow.send("join lobby").await;
+    alice.read_until(|line| line == "* large_slow joined lobby").await;
+
+    // The other 98 members of the room
+    let mut readers = Vec::new();
+    for i in 0..98 {
+        let mut member = connect(addr, &format!("large_{i}")).await;
+        member.send("join lobby").await;
+        alice.read_until(|line| line == format!("* large_{i} joined lobby")).await;
+        readers.push(tokio::spawn(async move {
+            member.read_until(|line| line == "large_alice: last").await;
+        }));
+    }
+
+    let text = "x".repeat(900);
+    for _ in 0..100 {
+        for _ in 0..20 {
+            alice.send(&format!("msg lobby {text}")).await;
+        }
+        for _ in 0..20 {
+            alice.read_until(|line| line.starts_with("OK ") || line.starts_with("ERR ")).await;
+        }
+    }
+    // Busy members may have had messages dropped too, so `last` is repeated
+    let started = tokio::time::Instant::now();
+    while !readers.iter().all(|member| member.is_finished()) {
+        assert!(started.elapsed() < READ_TIMEOUT, "a member was held up");
+        alice.send("msg lobby last").await;
+        alice.read_until(|line| line.starts_with("OK ") || line.starts_with("ERR ")).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    for member in readers {
+        member.await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn ping_is_answered() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "ping_alice").await;
+    alice.send("ping").await;
+    assert_eq!(alice.read_line().await, "pong");
+}
+
+#[tokio::test]
+async fn missing_pong_disconnects() {
+    let config = Config { ping_interval: Some(Duration::from_millis(200)), ..Config::default() };
+    let (addr, _shutdown) = start_server_with(config).await;
+    let mut alice = connect(addr, "pong_alice").await;
+    let mut bob = connect(addr, "pong_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* pong_bob joined lobby").await;
+
+    // Answered, so bob stays
+    let bob = tokio::spawn(async move {
+        loop {
+            match bob.read_line().await.as_str() {
+                "ping" => bob.send("pong").await,
+                "* pong_alice left lobby" => break,
+                line => assert_ne!(line, "* disconnected, no pong"),
+            }
+        }
+    });
+    // Never answered
+    alice.read_until(|line| line == "ping").await;
+    assert_eq!(alice.read_line().await, "* disconnected, no pong");
+    assert!(matches!(tokio::time::timeout(READ_TIMEOUT, alice.lines.next_line()).await, Ok(Ok(None))));
+    bob.await.unwrap();
+}
+
+#[tokio::test]
+async fn who_lists_members_sorted() {
+    let addr = start_server().await;
+    let mut carol = connect(addr, "who_carol").await;
+    let mut alice = connect(addr, "who_alice").await;
+    let mut bob = connect(addr, "who_bob").await;
+    for client in [&mut carol, &mut alice, &mut bob] {
+        client.send("join lobby").await;
+    }
+    carol.read_until(|line| line == "* who_bob joined lobby").await;
+
+    carol.send("who lobby").await;
+    assert_eq!(carol.read_line().await, "* users in lobby: who_alice, who_bob, who_carol");
+    bob.send("part lobby").await;
+    carol.read_until(|line| line == "* who_bob left lobby").await;
+    carol.send("who lobby").await;
+    assert_eq!(carol.read_line().await, "* users in lobby: who_alice, who_carol");
+}
+
+#[tokio::test]
+async fn private_message() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "pm_alice").await;
+    let mut bob = connect(addr, "pm_bob").await;
+
+    alice.send("pm pm_bob psst").await;
+    assert_eq!(bob.read_until(|line| !line.starts_with('*')).await, "[pm] pm_alice: psst");
+    alice.send("pm pm_nobody psst").await;
+    assert_eq!(alice.read_line().await, "* no such user pm_nobody [nosuchuser]");
+    bob.assert_silent().await;
+}
+
+#[tokio::test]
+async fn duplicate_username_refused() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let _alice = Client::login_raw(reader, writer, "dup_alice").await;
+
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut second = Client::raw(reader, writer).await;
+    second.send("dup_alice").await;
+    assert_eq!(second.read_line().await, "ERR taken");
+    // Still anonymous, so another name can be picked
+    second.send("dup_alice2").await;
+    assert!(second.read_line().await.starts_with("OK login "));
+}
+
+#[tokio::test]
+async fn usernames_are_per_server() {
+    let first = start_server().await;
+    let second = start_server().await;
+    let _alice = connect(first, "twice_alice").await;
+    // Taken on the first server only
+    let _alice_again = connect(second, "twice_alice").await;
+}
+
+#[tokio::test]
+async fn list_shows_rooms_and_member_counts() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "list_alice").await;
+    let mut bob = connect(addr, "list_bob").await;
+    alice.send("list").await;
+    assert_eq!(alice.read_line().await, "* rooms: none");
+
+    alice.send("join Lobby").await;
+    alice.send("join hall").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* list_bob joined lobby").await;
+    alice.send("list").await;
+    assert_eq!(alice.read_line().await, "* rooms: hall (1), Lobby (2)");
+}
+
+#[tokio::test]
+async fn me_action() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "me_alice").await;
+    let mut bob = connect(addr, "me_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* me_bob joined lobby").await;
+
+    alice.send("me lobby waves").await;
+    assert_eq!(bob.read_line().await, "* me_alice waves");
+    alice.assert_silent().await;
+}
+
+#[tokio::test]
+async fn joining_twice_is_one_membership() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "twice_alice").await;
+    let mut bob = connect(addr, "twice_bob").await;
+    bob.send("join lobby").await;
+    bob.send("who lobby").await;
+    bob.read_until(|line| line.starts_with("* users in lobby")).await;
+    alice.send("join lobby").await;
+    alice.send("join LOBBY").await;
+    assert_eq!(alice.read_line().await, "* already in lobby [alreadyjoined]");
+
+    alice.send("who lobby").await;
+    assert_eq!(alice.read_line().await, "* users in lobby: twice_alice, twice_bob");
+    bob.send("msg lobby once").await;
+    assert_eq!(alice.read_line().await, "twice_bob: once");
+    alice.assert_silent().await;
+}
+
+#[tokio::test]
+async fn messages_skip_the_sender() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "fanout_alice").await;
+    let mut bob = connect(addr, "fanout_bob").await;
+    let mut carol = connect(addr, "fanout_carol").await;
+    for client in [&mut alice, &mut bob, &mut carol] {
+        client.send("join lobby").await;
+    }
+    alice.read_until(|line| line == "* fanout_carol joined lobby").await;
+    bob.read_until(|line| line == "* fanout_carol joined lobby").await;
+
+    alice.send("msg lobby hi all").await;
+    assert_eq!(bob.read_line().await, "fanout_alice: hi all");
+    assert_eq!(carol.read_line().await, "fanout_alice: hi all");
+    alice.assert_silent().await;
+
+    // After parting, only the one who left is skipped
+    carol.send("part lobby").await;
+    alice.read_until(|line| line == "* fanout_carol left lobby").await;
+    bob.send("msg lobby still here").await;
+    assert_eq!(alice.read_line().await, "fanout_bob: still here");
+}
+
+#[tokio::test]
+async fn joining_replays_history() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "replay_alice").await;
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+    for i in 1..=5 {
+        alice.send(&format!("msg lobby message {i}")).await;
+        assert_eq!(alice.read_line().await, "OK msg");
+    }
+
+    let mut bob = connect(addr, "replay_bob").await;
+    bob.send("join lobby").await;
+    for i in 1..=5 {
+        assert_eq!(bob.read_line().await, format!("replay_alice: message {i}"));
+    }
+    bob.assert_silent().await;
+}
+
+#[tokio::test]
+async fn banner_and_prompt_on_connect() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut client = Client { lines: BufReader::new(reader).lines(), writer };
+    assert_eq!(client.read_line().await, "* welcome to chattery");
+    assert_eq!(client.read_until(|line| !line.starts_with("* ")).await, "enter username");
+}
+
+#[tokio::test]
+async fn flooding_is_rate_limited() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "ratelimit_alice").await;
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+
+    for i in 0..20 {
+        alice.send(&format!("msg lobby flood {i}")).await;
+    }
+    let mut replies = Vec::new();
+    for _ in 0..20 {
+        replies.push(alice.read_line().await);
+    }
+    assert!(replies.contains(&"OK msg".to_string()), "{replies:?}");
+    assert!(replies.contains(&"ERR ratelimited".to_string()), "{replies:?}");
+}
+
+#[tokio::test]
+async fn idle_connection_is_disconnected() {
+    let config = Config { idle_timeout: Duration::from_millis(300), ..Config::default() };
+    let (addr, _shutdown) = start_server_with(config).await;
+    let mut alice = connect(addr, "idle_alice").await;
+    let mut bob = connect(addr, "idle_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* idle_bob joined lobby").await;
+
+    // Bob keeps busy, so only alice goes idle
+    let bob = tokio::spawn(async move {
+        loop {
+            match tokio::time::timeout(Duration::from_millis(100), bob.lines.next_line()).await {
+                Ok(Ok(Some(line))) if line == "* idle_alice left lobby" => break,
+                Ok(Ok(Some(_))) => {}
+                Ok(_) => panic!("bob was disconnected"),
+                Err(_) => bob.send("ping").await,
+            }
+        }
+    });
+    alice.read_until(|line| line == "* disconnected due to inactivity").await;
+    assert!(matches!(tokio::time::timeout(READ_TIMEOUT, alice.lines.next_line()).await, Ok(Ok(None))));
+    tokio::time::timeout(READ_TIMEOUT, bob).await.expect("alice is still in lobby").unwrap();
+}
+
+#[tokio::test]
+async fn quit_says_goodbye_and_leaves() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "quit_alice").await;
+    let mut bob = connect(addr, "quit_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* quit_bob joined lobby").await;
+
+    alice.send("quit").await;
+    assert_eq!(alice.read_line().await, "* goodbye");
+    assert!(matches!(tokio::time::timeout(READ_TIMEOUT, alice.lines.next_line()).await, Ok(Ok(None))));
+    assert_eq!(bob.read_line().await, "* quit_alice left lobby");
+    bob.send("who lobby").await;
+    assert_eq!(bob.read_line().await, "* users in lobby: quit_bob");
+}
+
+#[tokio::test]
+async fn stats_count_messages_and_rooms() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "stats_alice").await;
+    alice.send("stats").await;
+    // Only this server's
+    assert_eq!(alice.read_line().await, "* server: 1 connections, 1 active, 0 messages routed, 0 rooms created, 0 open");
+
+    alice.send("join stats_room").await;
+    for i in 0..3 {
+        alice.send(&format!("msg stats_room {i}")).await;
+    }
+    alice.send("stats").await;
+    assert_eq!(alice.read_line().await, "* server: 1 connections, 1 active, 3 messages routed, 1 rooms created, 1 open");
+}
+
+#[tokio::test]
+async fn broadcast_reaches_every_joined_room_once() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "bcast_alice").await;
+    let mut others = Vec::new();
+    for room in ["one", "two", "three"] {
+        let mut other = connect(addr, &format!("bcast_{room}")).await;
+        other.send(&format!("join {room}")).await;
+        other.send(&format!("who {room}")).await;
+        other.read_until(|line| line.starts_with("* users in")).await;
+        alice.send(&format!("join {room}")).await;
+        other.read_until(|line| line == format!("* bcast_alice joined {room}")).await;
+        others.push(other);
+    }
+
+    alice.send("broadcast hello everyone").await;
+    for other in &mut others {
+        assert_eq!(other.read_line().await, "bcast_alice (broadcast): hello everyone");
+        other.assert_silent().await;
+    }
+}
+
+#[tokio::test]
+async fn full_room_refuses_joins() {
+    let config = Config { accept_rate: 1000, ..Config::default() };
+    let (addr, _shutdown) = start_server_with(config).await;
+    // Room for 256 members
+    let mut members = Vec::new();
+    for i in 0..256 {
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut member = Client::login_raw(reader, writer, &format!("full_{i}")).await;
+        member.send("join lobby").await;
+        member.read_until(|line| line == "OK join").await;
+        members.push(member);
+    }
+
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut late = Client::login_raw(reader, writer, "full_late").await;
+    late.send("join lobby").await;
+    assert_eq!(late.read_line().await, "ERR full");
+    let mut prose = connect(addr, "full_prose").await;
+    prose.send("join lobby").await;
+    assert_eq!(prose.read_line().await, "* room lobby is full [full]");
+    members[0].send("who lobby").await;
+    let who = members[0].read_until(|line| line.starts_with("* users in lobby")).await;
+    assert!(!who.contains("full_late") && !who.contains("full_prose"));
+}
+
+#[tokio::test]
+async fn echo_sends_own_messages_back() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "echo_alice").await;
+    alice.send("join lobby").await;
+    alice.send("echo on").await;
+    assert_eq!(alice.read_line().await, "* echo on");
+    alice.send("msg lobby hear me").await;
+    assert_eq!(alice.read_line().await, "echo_alice: hear me");
+
+    alice.send("echo off").await;
+    assert_eq!(alice.read_line().await, "* echo off");
+    alice.send("msg lobby quiet now").await;
+    alice.assert_silent().await;
+}
+
+#[tokio::test]
+async fn room_names_ignore_case() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "case_alice").await;
+    let mut bob = connect(addr, "case_bob").await;
+    alice.send("join Rust").await;
+    bob.send("join rUST").await;
+    alice.read_until(|line| line == "* case_bob joined rust").await;
+
+    bob.send("msg RUST hi").await;
+    assert_eq!(alice.read_line().await, "case_bob: hi");
+    alice.send("msg rust hi back").await;
+    assert_eq!(bob.read_line().await, "case_alice: hi back");
+}
+
+#[tokio::test]
+async fn rooms_per_user_are_capped() {
+    let config = Config { command_rate: 1000, ..Config::default() };
+    let (addr, _shutdown) = start_server_with(config).await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "cap_alice").await;
+    // Up to 64 rooms
+    for i in 0..64 {
+        alice.send(&format!("join room{i}")).await;
+        assert_eq!(alice.read_line().await, "OK join");
+    }
+    alice.send("join room64").await;
+    assert_eq!(alice.read_line().await, "ERR toomanyrooms");
+    alice.send("part room0").await;
+    assert_eq!(alice.read_line().await, "OK part");
+    alice.send("join room64").await;
+    assert_eq!(alice.read_line().await, "OK join");
+
+    let mut bob = connect(addr, "cap_bob").await;
+    for i in 0..64 {
+        bob.send(&format!("join bob{i}")).await;
+    }
+    bob.send("join bob64").await;
+    assert_eq!(bob.read_line().await, "* you have joined too many rooms [toomanyrooms]");
+}
+
+#[tokio::test]
+async fn operator_kicks() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "kick_alice").await;
+    let mut bob = connect(addr, "kick_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* kick_bob joined lobby").await;
+
+    // Only the operator, who created the room
+    bob.send("kick lobby kick_alice").await;
+    assert_eq!(bob.read_line().await, "* you are not an operator of lobby [notoperator]");
+    alice.send("kick lobby kick_nobody").await;
+    assert_eq!(alice.read_line().await, "* kick_nobody is not in lobby [notmember]");
+
+    alice.send("kick lobby kick_bob").await;
+    assert_eq!(alice.read_line().await, "* kick_bob was kicked from lobby");
+    assert_eq!(bob.read_line().await, "* you were kicked from lobby");
+    // Kicks aren't bans
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* kick_bob joined lobby").await;
+}
+
+#[tokio::test]
+async fn message_length_is_limited_in_chars() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "len_alice").await;
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+
+    alice.send(&format!("msg lobby {}", "a".repeat(2000))).await;
+    assert_eq!(alice.read_line().await, "ERR toolong");
+    // 1000 chars, twice as many bytes
+    alice.send(&format!("msg lobby {}", "é".repeat(1000))).await;
+    assert_eq!(alice.read_line().await, "OK msg");
+    alice.send(&format!("msg lobby {}", "é".repeat(1001))).await;
+    assert_eq!(alice.read_line().await, "ERR toolong");
+}
+
+#[tokio::test]
+async fn ipv6_loopback() {
+    let Ok(listener) = TcpListener::bind("[::1]:0").await else {
+        // No IPv6 here
+        return;
+    };
+    let server = Server::from_listener(Config::default(), listener).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+
+    let mut alice = connect(addr, "ipv6_alice").await;
+    let mut bob = connect(addr, "ipv6_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* ipv6_bob joined lobby").await;
+    bob.send("msg lobby over v6").await;
+    assert_eq!(alice.read_line().await, "ipv6_bob: over v6");
+}
+
+#[tokio::test]
+async fn control_chars_are_stripped() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "ctrl_alice").await;
+    let mut bob = connect(addr, "ctrl_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* ctrl_bob joined lobby").await;
+
+    bob.send("msg lobby clear\x1b[2J\rscreen\tok").await;
+    assert_eq!(alice.read_line().await, "ctrl_bob: clear[2Jscreen\tok");
+}
+
+#[tokio::test]
+async fn parse_shows_the_parsed_command() {
+    let (addr, _shutdown) = start_server_with(Config { debug_commands: true, ..Config::default() }).await;
+    let mut alice = connect(addr, "parse_alice").await;
+    alice.send("parse msg #x hi").await;
+    assert_eq!(alice.read_line().await, r##"Msg { room: "#x", msg: "hi" }"##);
+    alice.send("parse join").await;
+    assert_eq!(alice.read_line().await, "error: missing room name");
+}
+
+#[tokio::test]
+async fn debug_commands_are_off_by_default() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "nodebug_alice").await;
+    alice.send("parse msg #x hi").await;
+    assert_eq!(alice.read_line().await, "error: unknown command 'parse'");
+    alice.send("framestate").await;
+    assert_eq!(alice.read_line().await, "error: unknown command 'framestate'");
+}
+
+#[tokio::test]
+async fn multimsg_sends_each_message() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "multi_alice").await;
+    let mut bob = connect(addr, "multi_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* multi_bob joined lobby").await;
+
+    alice.send("multimsg lobby one\0two\0three").await;
+    for msg in ["one", "two", "three"] {
+        assert_eq!(bob.read_line().await, format!("multi_alice: {msg}"));
+    }
+    bob.assert_silent().await;
+}
+
+#[tokio::test]
+async fn greeting_on_join() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "greet_alice").await;
+    alice.send("join lobby").await;
+    alice.send("join hall").await;
+    alice.send("setgreeting lobby * hello there").await;
+    alice.send("who lobby").await;
+    alice.read_until(|line| line.starts_with("* users in lobby")).await;
+
+    let mut bob = connect(addr, "greet_bob").await;
+    bob.send("join lobby").await;
+    assert_eq!(bob.read_line().await, "* hello there");
+    // Only for the room it was set for
+    bob.send("join hall").await;
+    bob.assert_silent().await;
+    // Only by the operator
+    bob.send("setgreeting hall * mine now").await;
+    assert_eq!(bob.read_line().await, "* you are not an operator of hall [notoperator]");
+}
+
+#[tokio::test]
+async fn watching_without_joining() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "watch_alice").await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut bob = Client::login_raw(reader, writer, "watch_bob").await;
+    bob.send("watch lobby").await;
+    assert_eq!(bob.read_line().await, "ERR nosuchroom");
+    alice.send("join lobby").await;
+    alice.send("who lobby").await;
+    alice.read_until(|line| line.starts_with("* users in lobby")).await;
+
+    bob.send("watch lobby").await;
+    assert_eq!(bob.read_line().await, "OK watch");
+    alice.send("who lobby").await;
+    assert_eq!(alice.read_line().await, "* users in lobby: watch_alice");
+    alice.send("msg lobby anyone watching").await;
+    assert_eq!(bob.read_line().await, "watch_alice: anyone watching");
+
+    bob.send("unwatch lobby").await;
+    assert_eq!(bob.read_line().await, "OK unwatch");
+    alice.send("msg lobby gone").await;
+    bob.assert_silent().await;
+}
+
+#[tokio::test]
+async fn mystats_counts_messages() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "mystats_alice").await;
+    let mut bob = connect(addr, "mystats_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* mystats_bob joined lobby").await;
+    for i in 0..3 {
+        alice.send(&format!("msg lobby {i}")).await;
+    }
+    bob.read_until(|line| line == "mystats_alice: 2").await;
+    bob.send("msg lobby back").await;
+    alice.read_until(|line| line == "mystats_bob: back").await;
+
+    alice.send("mystats").await;
+    let stats = alice.read_line().await;
+    assert!(stats.contains(", sent 3 messages, "), "{stats}");
+    assert!(stats.contains(", in 1 rooms, "), "{stats}");
+    // Whatever else was written to alice, bob's message is in there
+    let received: usize = stats.split(", received ").nth(1).unwrap().split(' ').next().unwrap().parse().unwrap();
+    assert!(received >= 1, "{stats}");
+}
+
+// Anyone may authenticate as a bot that can only join and send messages
+struct Bots;
+
+impl Authenticator for Bots {
+    fn verify<'a>(&'a self, _user: &'a str, _token: &'a str) -> AuthFuture<'a> {
+        let allowed = ["join", "msg"].into_iter().map(String::from).collect();
+        Box::pin(std::future::ready(AuthResult::Restricted(allowed)))
+    }
+}
+
+#[tokio::test]
+async fn restricted_commands() {
+    let config = Config { authenticator: Some(Arc::new(Bots)), ..Config::default() };
+    let (addr, _shutdown) = start_server_with(config).await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut bot = Client::raw(reader, writer).await;
+    bot.send("restricted_bot").await;
+    assert_eq!(bot.read_line().await, "OK username");
+    bot.send("auth anything").await;
+    bot.read_until(|line| line.starts_with("OK login ")).await;
+
+    bot.send("join lobby").await;
+    assert_eq!(bot.read_line().await, "OK join");
+    bot.send("msg lobby beep").await;
+    assert_eq!(bot.read_line().await, "OK msg");
+    bot.send("who lobby").await;
+    assert_eq!(bot.read_line().await, "ERR notallowed");
+}
+
+#[tokio::test]
+async fn soft_wrap_to_declared_width() {
+    let (addr, _shutdown) = start_server_with(Config { soft_wrap: true, ..Config::default() }).await;
+    let mut alice = connect(addr, "wrap_alice").await;
+    let mut bob = connect(addr, "wrap_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* wrap_bob joined lobby").await;
+
+    alice.send("width 20").await;
+    // Wrapped as well
+    alice.send("who lobby").await;
+    assert_eq!(alice.read_line().await, "* users in lobby:");
+    assert_eq!(alice.read_line().await, "wrap_alice, wrap_bob");
+    bob.send("msg lobby the quick brown fox jumps").await;
+    assert_eq!(alice.read_line().await, "wrap_bob: the quick");
+    assert_eq!(alice.read_line().await, "brown fox jumps");
+
+    // Not unless the server allows it
+    let addr = start_server().await;
+    let mut carol = connect(addr, "wrap_carol").await;
+    carol.send("width 20").await;
+    assert_eq!(carol.read_line().await, "* soft wrap is disabled");
+}
+
+#[tokio::test]
+async fn echo_mode_echoes_lines() {
+    let (addr, _shutdown) = start_server_with(Config { echo: true, ..Config::default() }).await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut client = Client { lines: BufReader::new(reader).lines(), writer };
+    // No username, no commands, just the same line back
+    for line in ["join lobby", "not a command", "  spaces  "] {
+        client.send(line).await;
+        assert_eq!(client.read_line().await, line);
+    }
+}
+
+#[tokio::test]
+async fn typing_notices_skip_sender_and_history() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "typing_alice").await;
+    let mut bob = connect(addr, "typing_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* typing_bob joined lobby").await;
+
+    alice.send("typing lobby").await;
+    assert_eq!(bob.read_line().await, "* typing_alice is typing in lobby");
+    alice.assert_silent().await;
+
+    let mut carol = connect(addr, "typing_carol").await;
+    carol.send("join lobby").await;
+    carol.send("since lobby 0").await;
+    carol.assert_silent().await;
+}
+
+#[tokio::test]
+async fn room_motd_until_cleared() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "motd_alice").await;
+    alice.send("join lobby").await;
+    alice.send("setmotd lobby be nice").await;
+    alice.send("who lobby").await;
+    alice.read_until(|line| line.starts_with("* users in lobby")).await;
+
+    let mut bob = connect(addr, "motd_bob").await;
+    bob.send("join lobby").await;
+    assert_eq!(bob.read_line().await, "* motd: be nice");
+    // Only to the one joining
+    alice.read_until(|line| line == "* motd_bob joined lobby").await;
+    alice.assert_silent().await;
+
+    alice.send("clearmotd lobby").await;
+    alice.send("who lobby").await;
+    alice.read_until(|line| line.starts_with("* users in lobby")).await;
+    bob.send("part lobby").await;
+    alice.read_until(|line| line == "* motd_bob left lobby").await;
+    bob.send("join lobby").await;
+    bob.assert_silent().await;
+}
+
+#[tokio::test]
+async fn roster_sends_changes() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "roster_alice").await;
+    let mut bob = connect(addr, "roster_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* roster_bob joined lobby").await;
+
+    alice.send("roster lobby").await;
+    assert_eq!(alice.read_line().await, "* roster lobby: roster_alice, roster_bob");
+    alice.send("roster lobby").await;
+    assert_eq!(alice.read_line().await, "* roster lobby: no changes");
+
+    let mut carol = connect(addr, "roster_carol").await;
+    carol.send("join lobby").await;
+    alice.read_until(|line| line == "* roster_carol joined lobby").await;
+    bob.send("part lobby").await;
+    alice.read_until(|line| line == "* roster_bob left lobby").await;
+    alice.send("roster lobby").await;
+    assert_eq!(alice.read_line().await, "* roster lobby: +roster_carol -roster_bob");
+}
+
+#[tokio::test]
+async fn roomcaps_shows_the_room_settings() {
+    let (addr, _shutdown) = start_server_with(Config { history_len: 5, ..Config::default() }).await;
+    let mut alice = connect(addr, "roomcaps_alice").await;
+    alice.send("join lobby").await;
+    alice.send("roomcaps lobby").await;
+    assert_eq!(
+        alice.read_until(|line| line.starts_with("* roomcaps")).await,
+        "* roomcaps lobby: members 1/256, history 5, compress off, topic off, motd off, greeting off, link off"
+    );
+
+    alice.send("topic lobby rust").await;
+    alice.send("setmotd lobby be nice").await;
+    let mut bob = connect(addr, "roomcaps_bob").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* roomcaps_bob joined lobby").await;
+    alice.send("roomcaps lobby").await;
+    assert_eq!(
+        alice.read_until(|line| line.starts_with("* roomcaps")).await,
+        "* roomcaps lobby: members 2/256, history 5, compress off, topic on, motd on, greeting off, link off"
+    );
+
+    // Only for members
+    let mut carol = connect(addr, "roomcaps_carol").await;
+    carol.send("roomcaps lobby").await;
+    assert_eq!(carol.read_line().await, "* you are not in lobby [notmember]");
+}
+
+#[tokio::test]
+async fn rejections_share_a_format() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "reject_alice").await;
+    let mut bob = connect(addr, "reject_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* reject_bob joined lobby").await;
+    alice.send("ban lobby reject_bob").await;
+    bob.read_until(|line| line.ends_with("[banned]")).await;
+
+    for (command, reply) in [
+        ("msg nowhere hi", "* no such room nowhere [nosuchroom]"),
+        ("who nowhere", "* you are not in nowhere [notmember]"),
+        ("join lobby", "* you are banned from lobby [banned]"),
+        ("config", "* only admins can do that [notadmin]"),
+        ("broadcast hi", "* you are not in any rooms [notmember]"),
+        ("pm reject_nobody hi", "* no such user reject_nobody [nosuchuser]"),
+        ("admin wrong", "* invalid token [invalidtoken]"),
+    ] {
+        bob.send(command).await;
+        assert_eq!(bob.read_line().await, reply, "{command}");
+    }
+    bob.send("ban lobby reject_alice").await;
+    assert_eq!(bob.read_line().await, "* you are not an operator of lobby [notoperator]");
+}
+
+#[tokio::test]
+async fn verbose_shows_dropped_commands() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "verbose_alice").await;
+    alice.send("join lobby").await;
+    alice.send("typing lobby").await;
+    alice.send("typing lobby").await;
+    alice.assert_silent().await;
+
+    alice.send("verbose on").await;
+    assert_eq!(alice.read_line().await, "* verbose on");
+    alice.send("typing lobby").await;
+    assert_eq!(alice.read_line().await, "* typing notice dropped: rate limited");
+    alice.send("verbose off").await;
+    assert_eq!(alice.read_line().await, "* verbose off");
+    alice.send("typing lobby").await;
+    alice.assert_silent().await;
+
+    // Messages from muted users
+    let mut bob = connect(addr, "verbose_bob").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* verbose_bob joined lobby").await;
+    alice.send("mute verbose_bob").await;
+    assert_eq!(alice.read_line().await, "* muted verbose_bob");
+    bob.send("msg lobby quiet").await;
+    alice.assert_silent().await;
+    alice.send("verbose on").await;
+    assert_eq!(alice.read_line().await, "* verbose on");
+    bob.send("msg lobby loud").await;
+    assert_eq!(alice.read_line().await, "* message in lobby dropped: sender muted");
+    bob.send("pm verbose_alice loud").await;
+    assert_eq!(alice.read_line().await, "* pm from verbose_bob dropped: sender muted");
+
+    // Commands over the rate limit
+    let (addr, _shutdown) = start_server_with(Config { command_rate: 2, ..Config::default() }).await;
+    let mut carol = connect(addr, "verbose_carol").await;
+    for _ in 0..2 {
+        carol.send("list").await;
+        assert_eq!(carol.read_line().await, "* rooms: none");
+    }
+    carol.send("list").await;
+    carol.assert_silent().await;
+    let mut dave = connect(addr, "verbose_dave").await;
+    dave.send("verbose on").await;
+    assert_eq!(dave.read_line().await, "* verbose on");
+    dave.send("list").await;
+    assert_eq!(dave.read_line().await, "* rooms: none");
+    dave.send("list").await;
+    assert_eq!(dave.read_line().await, "* rate limit exceeded, slow down");
+}
+
+#[tokio::test]
+async fn anonymous_watchers_are_read_only() {
+    let (addr, _shutdown) = start_server_with(Config { anonymous_watchers: 10, ..Config::default() }).await;
+    let mut alice = connect(addr, "anon_alice").await;
+    alice.send("join lobby").await;
+    alice.send("who lobby").await;
+    alice.read_until(|line| line.starts_with("* users in lobby")).await;
+
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut anon = Client::raw(reader, writer).await;
+    anon.send("watch lobby").await;
+    assert_eq!(anon.read_line().await, "OK watch");
+    alice.send("msg lobby hello lurkers").await;
+    assert_eq!(anon.read_line().await, "anon_alice: hello lurkers");
+    anon.send("msg lobby hi").await;
+    assert_eq!(anon.read_line().await, "ERR readonly");
+}
+
+#[tokio::test]
+async fn message_to_emptied_room() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "emptied_alice").await;
+    alice.send("join lobby").await;
+    alice.send("part lobby").await;
+    alice.send("msg lobby anyone").await;
+    assert_eq!(alice.read_line().await, "* no such room lobby [nosuchroom]");
+}
+
+#[tokio::test]
+async fn message_to_room_never_joined() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "outsider_alice").await;
+    let mut bob = connect(addr, "outsider_bob").await;
+    bob.send("join lobby").await;
+    bob.send("who lobby").await;
+    bob.read_until(|line| line.starts_with("* users in lobby")).await;
+
+    alice.send("msg lobby let me in").await;
+    assert_eq!(alice.read_line().await, "* you are not in lobby [notmember]");
+    bob.assert_silent().await;
+}
+
+#[tokio::test]
+async fn strict_username_disconnects_commands() {
+    let (addr, _shutdown) = start_server_with(Config { strict_username: true, ..Config::default() }).await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut mallory = Client::raw(reader, writer).await;
+    mallory.send("join lobby").await;
+    assert_eq!(mallory.read_line().await, "ERR nousername");
+    assert!(matches!(tokio::time::timeout(READ_TIMEOUT, mallory.lines.next_line()).await, Ok(Ok(None))));
+
+    // A username first is fine
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "strict_alice").await;
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+}
+
+#[tokio::test]
+async fn schedules_lists_and_cancels() {
+    let addr = start_server().await;
+    let mut alice = connect(addr, "schedules_alice").await;
+    let mut bob = connect(addr, "schedules_bob").await;
+    alice.send("join lobby").await;
+    bob.send("join lobby").await;
+    alice.read_until(|line| line == "* schedules_bob joined lobby").await;
+
+    alice.send("schedule lobby 1 first").await;
+    assert_eq!(alice.read_line().await, "* scheduled message 1 in 1s");
+    alice.send("schedule lobby 1 second, with a longer message").await;
+    assert_eq!(alice.read_line().await, "* scheduled message 2 in 1s");
+    alice.send("schedules").await;
+    assert_eq!(alice.read_line().await, "* 2 scheduled messages");
+    assert!(alice.read_line().await.starts_with("* 1: lobby in "));
+    assert!(alice.read_line().await.ends_with(": second, with a longe..."));
+
+    alice.send("cancelschedule 1").await;
+    assert_eq!(alice.read_line().await, "* cancelled scheduled message 1");
+    assert_eq!(bob.read_line().await, "schedules_alice: second, with a longer message");
+    bob.assert_silent().await;
+    alice.send("schedules").await;
+    assert_eq!(alice.read_line().await, "* 0 scheduled messages");
+}
+
+#[tokio::test]
+async fn framestate_counts_partial_bytes() {
+    let (addr, _shutdown) = start_server_with(Config { debug_commands: true, ..Config::default() }).await;
+    let mut alice = connect(addr, "framestate_alice").await;
+    // Sent together, so the partial line is already buffered when `framestate` is handled
+    alice.writer.write_all(b"framestate\npart").await.unwrap();
+    let state = alice.read_line().await;
+    assert!(state.ends_with(", 4 bytes awaiting a newline"), "{state}");
+}
+
+#[tokio::test]
+async fn invalid_utf8_username() {
+    let addr = start_server().await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut client = Client::raw(reader, writer).await;
+    client.writer.write_all(b"\xff\xfe\n").await.unwrap();
+    assert_eq!(client.read_line().await, "ERR invalidusername");
+    // Still anonymous, and still connected
+    client.send("join lobby").await;
+    assert_eq!(client.read_line().await, "ERR invalidusername");
+    client.send("utf8_alice").await;
+    assert!(client.read_line().await.starts_with("OK login "));
+}
+
+#[tokio::test]
+async fn connections_accepted_at_the_accept_rate() {
+    let (addr, _shutdown) = start_server_with(Config { accept_rate: 5, ..Config::default() }).await;
+    let started = tokio::time::Instant::now();
+    let mut clients = Vec::new();
+    for _ in 0..10 {
+        clients.push(tokio::spawn(async move {
+            let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+            // Answered once accepted
+            Client::raw(reader, writer).await;
+        }));
+    }
+    for client in clients {
+        client.await.unwrap();
+    }
+    // 5 straight away, and the other 5 over the next second. None refused
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(800), "{elapsed:?}");
+}
+
+#[tokio::test]
+async fn recipient_leaving_mid_broadcast() {
+    let config = Config { command_rate: 100_000, ..Config::default() };
+    let (addr, _shutdown) = start_server_with(config).await;
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice = Client::login_raw(reader, writer, "midway_alice").await;
+    let mut bob = connect(addr, "midway_bob").await;
+    let mut carol = connect(addr, "midway_carol").await;
+    alice.send("join lobby").await;
+    assert_eq!(alice.read_line().await, "OK join");
+    bob.send("join lobby").await;
+    carol.send("join lobby").await;
+    alice.read_until(|line| line == "* midway_carol joined lobby").await;
+    let carol = tokio::spawn(async move {
+        carol.read_until(|line| line == "midway_alice: last").await;
+        carol
+    });
+
+    // Bob hangs up after the first message, with plenty more on the way to him
+    let text = "x".repeat(500);
+    alice.send(&format!("msg lobby {text}")).await;
+    assert_eq!(alice.read_line().await, "OK msg");
+    bob.read_until(|line| line.starts_with("midway_alice: ")).await;
+    for _ in 0..20 {
+        alice.send(&format!("msg lobby {text}")).await;
+    }
+    drop(bob);
+    for _ in 0..50 {
+        for _ in 0..20 {
+            alice.read_until(|line| line.starts_with("OK ") || line.starts_with("ERR ")).await;
+        }
+        for _ in 0..20 {
+            alice.send(&format!("msg lobby {text}")).await;
+        }
+    }
+    alice.send("msg lobby last").await;
+    let _carol = tokio::time::timeout(READ_TIMEOUT, carol).await.expect("carol was held up").unwrap();
+    alice.send("who lobby").await;
+    alice.read_until(|line| line == "* users in lobby: midway_alice, midway_carol").await;
+}